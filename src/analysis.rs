@@ -0,0 +1,372 @@
+use crate::models::PassableValue;
+use crate::library::{library_function, library_functions};
+use crate::DisplayableValue;
+use cel_interpreter::{Context, Expression, Value};
+use cel_parser::Member;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/**
+ * Visits the expression and all of its sub-expressions in pre-order.
+ * @param expression The expression to walk
+ * @param visit The function called for every visited expression
+ */
+pub(crate) fn walk<'a>(expression: &'a Expression, visit: &mut dyn FnMut(&'a Expression)) {
+    visit(expression);
+    walk_children(expression, &mut |it| walk(it, visit));
+}
+
+/**
+ * Visits the expression and the sub-expressions evaluated whenever it is, in pre-order.
+ * The right side of `||`, the branches of a ternary, the bodies of macros and the fallback of `maybe` are skipped,
+ * since the evaluation may never reach them.
+ * @param expression The expression to walk
+ * @param visit The function called for every visited expression
+ */
+pub(crate) fn walk_evaluated<'a>(expression: &'a Expression, visit: &mut dyn FnMut(&'a Expression)) {
+    visit(expression);
+    let mut evaluated = |it: &'a Expression| walk_evaluated(it, visit);
+    match expression {
+        Expression::Or(left, _) => evaluated(left),
+        Expression::Ternary(condition, ..) => evaluated(condition),
+        Expression::FunctionCall(function, target, args) => {
+            let count = match function.as_ref() {
+                Expression::Ident(name) if name.as_str() == "has" || MACROS.contains(&name.as_str()) => 0,
+                Expression::Ident(name) if name.as_str() == "maybe" => args.len().saturating_sub(1),
+                _ => args.len(),
+            };
+            target.iter().for_each(|it| evaluated(it));
+            args.iter().take(count).for_each(evaluated);
+        }
+        expression => walk_children(expression, &mut evaluated),
+    }
+}
+
+/**
+ * Checks if the expression is built only from literals, so it evaluates to the same value in any context.
+ */
+pub(crate) fn is_constant(expression: &Expression) -> bool {
+    let mut constant = true;
+    walk(expression, &mut |it| {
+        if matches!(it, Expression::Ident(_) | Expression::FunctionCall(..)) {
+            constant = false;
+        }
+    });
+    constant
+}
+
+/**
+ * Finds the method calls on an identifier in the expression whose arguments are constant and which are made whenever
+ * the expression is evaluated, together with the values of those arguments. These are the calls to host properties
+ * if the identifier is a host object declaring them, i.e. `computed.daysSinceEvent("paywall_open")`.
+ * @param expression The expression to search
+ * @return The identifier and name of each called method and its resolved arguments
+ */
+pub(crate) fn constant_host_calls(expression: &Expression) -> Vec<(Arc<String>, Arc<String>, Vec<Value>)> {
+    let ctx = Context::default();
+    let mut calls = vec![];
+    walk_evaluated(expression, &mut |it| {
+        if let Expression::FunctionCall(function, Some(target), args) = it {
+            if let (Expression::Ident(name), Expression::Ident(object)) = (function.as_ref(), target.as_ref()) {
+                if args.iter().all(is_constant) {
+                    if let Ok(args) = args.iter().map(|arg| ctx.resolve(arg)).collect() {
+                        calls.push((object.clone(), name.clone(), args));
+                    }
+                }
+            }
+        }
+    });
+    calls
+}
+
+/**
+ * Functions built into the interpreter, and `maybe` whose arguments are not evaluated like the ones of other functions.
+ * The other functions added by this library are declared with their signature in `library::library_functions`.
+ */
+pub(crate) const BUILTIN_FUNCTIONS: &[&str] = &[
+    "contains",
+    "size",
+    "has",
+    "map",
+    "filter",
+    "all",
+    "max",
+    "startsWith",
+    "endsWith",
+    "matches",
+    "duration",
+    "timestamp",
+    "string",
+    "bytes",
+    "double",
+    "exists",
+    "exists_one",
+    "int",
+    "uint",
+    "maybe",
+];
+
+/**
+ * Checks if the function is available in every evaluation context, either built into the interpreter or added by this
+ * library outside of an extension.
+ */
+pub(crate) fn is_builtin(name: &str) -> bool {
+    BUILTIN_FUNCTIONS.contains(&name) || library_function(name, &[]).is_some()
+}
+
+/**
+ * Functions which bind their first argument as a variable available in the second one, i.e. `list.map(x, x * 2)`.
+ */
+pub(crate) const MACROS: &[&str] = &["map", "filter", "all", "exists", "exists_one"];
+
+/**
+ * Objects whose members are resolved by the host context.
+ */
+pub(crate) const HOST_OBJECTS: &[&str] = &["computed", "device"];
+
+/**
+ * The result of statically analyzing an expression, describing everything it references
+ * so the host can gather only the data the expression needs before evaluating it.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct ExpressionAnalysis {
+    /// Top-level variables, excluding the `computed`, `device` and `namespaces` objects.
+    pub(crate) variables: Vec<String>,
+    /// Static member paths, i.e. `user.should_display` or `device.osVersion`.
+    pub(crate) members: Vec<String>,
+    /// Names of the `computed` properties, either accessed or called.
+    pub(crate) computed: Vec<String>,
+    /// Names of the `device` properties, either accessed or called.
+    pub(crate) device: Vec<String>,
+    /// Names of the properties of each of the declared `namespaces` the expression uses, either accessed or called.
+    pub(crate) namespaces: BTreeMap<String, Vec<String>>,
+    /// Every distinct function call.
+    pub(crate) functions: Vec<FunctionReference>,
+    /// Names of called functions which are neither built in nor host properties.
+    pub(crate) unknown_functions: Vec<String>,
+    pub(crate) uses_unknown_functions: bool,
+}
+
+/**
+ * A function called in an expression.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct FunctionReference {
+    pub(crate) name: String,
+    /// The member path the function is called on, if it is a method call on a static path, i.e. `computed`.
+    pub(crate) target: Option<String>,
+    /// The value of each argument if it is a literal, `null` otherwise.
+    pub(crate) args: Vec<Option<PassableValue>>,
+}
+
+/**
+ * Statically analyze an expression, listing the variables, members and functions it references.
+ * @param expression The expression to analyze
+ * @param namespaces The host objects declared next to `computed` and `device`, i.e. `subscription`
+ */
+pub(crate) fn analyze(expression: &Expression, namespaces: &[String]) -> ExpressionAnalysis {
+    let mut analyzer = Analyzer {
+        namespaces,
+        ..Default::default()
+    };
+    analyzer.visit(expression);
+    let unknown_functions: Vec<String> = analyzer.unknown_functions.into_iter().collect();
+    let mut host_properties = analyzer.host_properties;
+    let mut properties = |object: &str| host_properties.remove(object).unwrap_or_default().into_iter().collect();
+    ExpressionAnalysis {
+        variables: analyzer.variables.into_iter().collect(),
+        members: analyzer.members.into_iter().collect(),
+        computed: properties("computed"),
+        device: properties("device"),
+        namespaces: host_properties
+            .into_iter()
+            .map(|(object, properties)| (object, properties.into_iter().collect()))
+            .collect(),
+        functions: analyzer.functions,
+        uses_unknown_functions: !unknown_functions.is_empty(),
+        unknown_functions,
+    }
+}
+
+#[derive(Default)]
+struct Analyzer<'a> {
+    namespaces: &'a [String],
+    variables: BTreeSet<String>,
+    members: BTreeSet<String>,
+    host_properties: BTreeMap<String, BTreeSet<String>>,
+    functions: Vec<FunctionReference>,
+    unknown_functions: BTreeSet<String>,
+    // Variables bound by the macros currently being visited
+    bound: Vec<String>,
+}
+
+impl Analyzer<'_> {
+    fn visit(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Ident(name) => self.reference(name, &[]),
+            Expression::Member(inner, member) => match member_path(expression) {
+                Some((root, path)) => self.reference(&root, &path),
+                None => {
+                    self.visit(inner);
+                    match member.as_ref() {
+                        Member::Attribute(_) => {}
+                        Member::Index(index) => self.visit(index),
+                        Member::Fields(fields) => fields.iter().for_each(|(_, it)| self.visit(it)),
+                    }
+                }
+            },
+            Expression::FunctionCall(function, target, args) => {
+                let name = match function.as_ref() {
+                    Expression::Ident(name) => name,
+                    _ => {
+                        walk_children(expression, &mut |it| self.visit(it));
+                        return;
+                    }
+                };
+                let target_path = target.as_ref().and_then(|it| member_path(it));
+                let host_object = match &target_path {
+                    Some((root, path)) if path.is_empty() && self.is_host_object(root) => Some(root.clone()),
+                    _ => None,
+                };
+                match &host_object {
+                    Some(object) => self.host_property(object, name),
+                    None => {
+                        if let Some(target) = target {
+                            self.visit(target);
+                        }
+                        // The extensions are not known here, so their functions are known too
+                        let is_library = library_functions().iter().any(|it| it.name == name.as_str());
+                        if !BUILTIN_FUNCTIONS.contains(&name.as_str()) && !is_library {
+                            self.unknown_functions.insert(name.to_string());
+                        }
+                    }
+                }
+                let reference = FunctionReference {
+                    name: name.to_string(),
+                    target: target_path.map(|(root, path)| std::iter::once(root).chain(path).collect::<Vec<_>>().join(".")),
+                    args: args.iter().map(literal_value).collect(),
+                };
+                if !self.functions.contains(&reference) {
+                    self.functions.push(reference);
+                }
+                let binds = match args.as_slice() {
+                    [Expression::Ident(variable), _] if target.is_some() && MACROS.contains(&name.as_str()) => {
+                        Some(variable.to_string())
+                    }
+                    _ => None,
+                };
+                match binds {
+                    Some(variable) => {
+                        self.bound.push(variable);
+                        self.visit(&args[1]);
+                        self.bound.pop();
+                    }
+                    None => args.iter().for_each(|it| self.visit(it)),
+                }
+            }
+            _ => walk_children(expression, &mut |it| self.visit(it)),
+        }
+    }
+
+    fn is_host_object(&self, name: &str) -> bool {
+        (HOST_OBJECTS.contains(&name) || self.namespaces.iter().any(|it| it == name))
+            && !self.bound.iter().any(|it| it == name)
+    }
+
+    fn host_property(&mut self, object: &str, name: &str) {
+        self.host_properties
+            .entry(object.to_string())
+            .or_default()
+            .insert(name.to_string());
+    }
+
+    fn reference(&mut self, root: &str, path: &[String]) {
+        if self.bound.iter().any(|it| it == root) {
+            return;
+        }
+        if self.is_host_object(root) {
+            if let Some(property) = path.first() {
+                self.host_property(root, property);
+            }
+        } else {
+            self.variables.insert(root.to_string());
+        }
+        if !path.is_empty() {
+            self.members.insert(format!("{}.{}", root, path.join(".")));
+        }
+    }
+}
+
+/**
+ * Visits only the direct children of the expression.
+ */
+pub(crate) fn walk_children<'a>(expression: &'a Expression, visit: &mut dyn FnMut(&'a Expression)) {
+    match expression {
+        Expression::Arithmetic(left, _, right)
+        | Expression::Relation(left, _, right)
+        | Expression::Or(left, right)
+        | Expression::And(left, right) => {
+            visit(left);
+            visit(right);
+        }
+        Expression::Ternary(condition, if_true, if_false) => {
+            visit(condition);
+            visit(if_true);
+            visit(if_false);
+        }
+        Expression::Unary(_, expression) => visit(expression),
+        Expression::Member(expression, member) => {
+            visit(expression);
+            match member.as_ref() {
+                Member::Attribute(_) => {}
+                Member::Index(index) => visit(index),
+                Member::Fields(fields) => fields.iter().for_each(|(_, it)| visit(it)),
+            }
+        }
+        Expression::FunctionCall(function, target, args) => {
+            visit(function);
+            if let Some(target) = target {
+                visit(target);
+            }
+            args.iter().for_each(&mut *visit);
+        }
+        Expression::List(items) => items.iter().for_each(&mut *visit),
+        Expression::Map(entries) => entries.iter().for_each(|(key, value)| {
+            visit(key);
+            visit(value);
+        }),
+        Expression::Atom(_) | Expression::Ident(_) => {}
+    }
+}
+
+/**
+ * Splits a chain of attribute accesses such as `user.profile.name` into its root identifier and attribute names.
+ * Returns `None` if the expression is not a static member path.
+ */
+fn member_path(expression: &Expression) -> Option<(String, Vec<String>)> {
+    match expression {
+        Expression::Ident(name) => Some((name.to_string(), vec![])),
+        Expression::Member(inner, member) => match member.as_ref() {
+            Member::Attribute(name) => member_path(inner).map(|(root, mut path)| {
+                path.push(name.to_string());
+                (root, path)
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/**
+ * Resolves the value of a constant expression, or `None` if it depends on the context.
+ */
+fn literal_value(expression: &Expression) -> Option<PassableValue> {
+    if !is_constant(expression) {
+        return None;
+    }
+    Context::default()
+        .resolve(expression)
+        .ok()
+        .map(|value| DisplayableValue(value).to_passable())
+}
@@ -1,4 +1,4 @@
-use crate::models::{PassableMap, PassableValue};
+use crate::models::{EvaluationOptions, PassableMap, PassableValue};
 use cel_parser::Member::{Attribute, Fields, Index};
 use cel_parser::{ArithmeticOp, Atom, Expression, Member, RelationOp, UnaryOp};
 use serde::{Deserialize, Serialize};
@@ -10,7 +10,15 @@ pub(crate) struct ASTExecutionContext {
     pub(crate) variables: PassableMap,
     pub(crate) expression: JSONExpression,
     pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
-    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    /// Host objects other than `computed` and `device`, mapped to the properties they declare,
+    /// i.e. `{"subscription": {"daysLeft": []}}`.
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    /// Return an explanation of the evaluation next to its result.
+    #[serde(default)]
+    pub(crate) explain: bool,
+    #[serde(flatten)]
+    pub(crate) options: EvaluationOptions
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -0,0 +1,671 @@
+use crate::analysis::{HOST_OBJECTS, MACROS};
+use crate::library::library_function;
+use crate::models::Extension;
+use crate::printer;
+use cel_interpreter::Expression;
+use cel_parser::{ArithmeticOp, Atom, Member, RelationOp, UnaryOp};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/**
+ * Static type of a CEL value. Simple types are written as their name, i.e. `"int"`,
+ * and compound types as an object, i.e. `{"list": "string"}` or `{"object": {"name": "string"}}`.
+ * `dyn` is compatible with every type and is used wherever the type cannot be known statically.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CelType {
+    Int,
+    #[serde(rename = "uint")]
+    UInt,
+    Float,
+    String,
+    Bytes,
+    Bool,
+    Null,
+    Timestamp,
+    Duration,
+    Dyn,
+    /// A list with items of the given type.
+    List(Box<CelType>),
+    /// A map with values of the given type, whose keys are not known statically.
+    Map(Box<CelType>),
+    /// A map with known keys, each having its own type.
+    Object(BTreeMap<String, CelType>),
+}
+
+impl CelType {
+    fn is_numeric(&self) -> bool {
+        matches!(self, CelType::Int | CelType::UInt | CelType::Float)
+    }
+
+    /**
+     * Checks if a value of the `other` type can be used where a value of this type is expected.
+     */
+    fn accepts(&self, other: &CelType) -> bool {
+        match (self, other) {
+            (CelType::Dyn, _) | (_, CelType::Dyn) => true,
+            (CelType::List(expected), CelType::List(actual)) | (CelType::Map(expected), CelType::Map(actual)) => {
+                expected.accepts(actual)
+            }
+            (CelType::Map(expected), CelType::Object(fields)) => fields.values().all(|it| expected.accepts(it)),
+            (CelType::Object(_), CelType::Map(_)) => true,
+            (CelType::Object(expected), CelType::Object(actual)) => expected
+                .iter()
+                .all(|(name, it)| actual.get(name).map(|actual| it.accepts(actual)).unwrap_or(false)),
+            (expected, actual) => expected == actual,
+        }
+    }
+
+    /**
+     * The type of a value which is either of this type or of the `other` one.
+     */
+    fn join(self, other: CelType) -> CelType {
+        if self == other {
+            self
+        } else {
+            CelType::Dyn
+        }
+    }
+}
+
+impl fmt::Display for CelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CelType::List(item) => write!(f, "list({})", item),
+            CelType::Map(value) => write!(f, "map({})", value),
+            CelType::Object(_) => write!(f, "object"),
+            simple => write!(f, "{}", serde_json::to_value(simple).unwrap().as_str().unwrap_or_default()),
+        }
+    }
+}
+
+/**
+ * Types of the variables and signatures of the host properties an expression can use.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct Declarations {
+    #[serde(default)]
+    pub(crate) variables: HashMap<String, CelType>,
+    #[serde(default)]
+    pub(crate) computed: HashMap<String, FunctionSignature>,
+    #[serde(default)]
+    pub(crate) device: HashMap<String, FunctionSignature>,
+    /// Signatures of the properties of the other host objects, by object, i.e. `{"subscription": {"daysLeft": ...}}`.
+    #[serde(default)]
+    pub(crate) namespaces: HashMap<String, HashMap<String, FunctionSignature>>,
+    /// The extensions the expression is evaluated with, i.e. `["strings"]`.
+    #[serde(default)]
+    pub(crate) extensions: Vec<Extension>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct FunctionSignature {
+    #[serde(default)]
+    pub(crate) args: Vec<CelType>,
+    pub(crate) result: CelType,
+}
+
+/**
+ * The result of type checking an expression: its inferred type and every problem found.
+ * The expression is valid if there are no errors.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct CheckResult {
+    #[serde(rename = "type")]
+    pub(crate) result_type: CelType,
+    pub(crate) errors: Vec<CheckError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct CheckError {
+    pub(crate) kind: CheckErrorKind,
+    pub(crate) message: String,
+    /// The CEL source of the sub-expression the error was found in.
+    pub(crate) expression: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CheckErrorKind {
+    /// A variable, key or host property that is not declared.
+    UndeclaredReference,
+    /// A function that is neither built in nor declared by the host.
+    UnknownFunction,
+    /// A function called with the wrong number of arguments.
+    WrongArity,
+    /// An operation or function applied to values of an unsupported type.
+    TypeMismatch,
+}
+
+/**
+ * Type checks an expression against the declarations without evaluating it.
+ * @param expression The expression to check
+ * @param declarations The types of the variables and the signatures of the `computed`, `device` and `namespaces`
+ * properties
+ */
+pub(crate) fn check(expression: &Expression, declarations: &Declarations) -> CheckResult {
+    let mut checker = Checker {
+        declarations,
+        scopes: vec![],
+        errors: vec![],
+    };
+    let result_type = checker.check(expression);
+    CheckResult {
+        result_type,
+        errors: checker.errors,
+    }
+}
+
+struct Checker<'a> {
+    declarations: &'a Declarations,
+    // Variables bound by the macros currently being checked
+    scopes: Vec<(String, CelType)>,
+    errors: Vec<CheckError>,
+}
+
+impl<'a> Checker<'a> {
+    fn check(&mut self, expression: &Expression) -> CelType {
+        match expression {
+            Expression::Atom(atom) => match atom {
+                Atom::Int(_) => CelType::Int,
+                Atom::UInt(_) => CelType::UInt,
+                Atom::Float(_) => CelType::Float,
+                Atom::String(_) => CelType::String,
+                Atom::Bytes(_) => CelType::Bytes,
+                Atom::Bool(_) => CelType::Bool,
+                Atom::Null => CelType::Null,
+            },
+            Expression::Ident(name) => self.identifier(expression, name),
+            Expression::Ternary(condition, if_true, if_false) => {
+                self.check(condition);
+                let if_true = self.check(if_true);
+                if_true.join(self.check(if_false))
+            }
+            // `||` returns the first truthy side rather than a boolean
+            Expression::Or(left, right) => {
+                let left = self.check(left);
+                left.join(self.check(right))
+            }
+            Expression::And(left, right) => {
+                self.check(left);
+                self.check(right);
+                CelType::Bool
+            }
+            Expression::Unary(op, operand) => {
+                let operand = self.check(operand);
+                match op {
+                    UnaryOp::Not | UnaryOp::DoubleNot => CelType::Bool,
+                    UnaryOp::Minus if matches!(operand, CelType::Int | CelType::Float | CelType::Dyn) => operand,
+                    UnaryOp::DoubleMinus if operand.is_numeric() || operand == CelType::Dyn => operand,
+                    _ => self.mismatch(expression, format!("Cannot negate a value of type {}", operand)),
+                }
+            }
+            Expression::Arithmetic(left, op, right) => {
+                let left = self.check(left);
+                let right = self.check(right);
+                self.arithmetic(expression, op, left, right)
+            }
+            Expression::Relation(left, op, right) => {
+                let left = self.check(left);
+                let right = self.check(right);
+                self.relation(expression, op, left, right);
+                CelType::Bool
+            }
+            Expression::Member(target, member) => self.member(expression, target, member),
+            Expression::FunctionCall(function, target, args) => match function.as_ref() {
+                Expression::Ident(name) => self.function_call(expression, name, target.as_deref(), args),
+                _ => {
+                    self.check(function);
+                    CelType::Dyn
+                }
+            },
+            Expression::List(items) => {
+                let item = items
+                    .iter()
+                    .map(|it| self.check(it))
+                    .reduce(CelType::join)
+                    .unwrap_or(CelType::Dyn);
+                CelType::List(Box::new(item))
+            }
+            Expression::Map(entries) => {
+                let mut value_type = None;
+                for (key, value) in entries {
+                    let key_type = self.check(key);
+                    if !matches!(
+                        key_type,
+                        CelType::Int | CelType::UInt | CelType::Bool | CelType::String | CelType::Dyn
+                    ) {
+                        self.mismatch(key, format!("A value of type {} cannot be used as a map key", key_type));
+                    }
+                    let value = self.check(value);
+                    value_type = Some(match value_type {
+                        Some(it) => CelType::join(it, value),
+                        None => value,
+                    });
+                }
+                CelType::Map(Box::new(value_type.unwrap_or(CelType::Dyn)))
+            }
+        }
+    }
+
+    fn identifier(&mut self, expression: &Expression, name: &str) -> CelType {
+        if let Some((_, bound)) = self.scopes.iter().rev().find(|(it, _)| it == name) {
+            return bound.clone();
+        }
+        if let Some(declared) = self.declarations.variables.get(name) {
+            return declared.clone();
+        }
+        if HOST_OBJECTS.contains(&name) || self.declarations.namespaces.contains_key(name) {
+            return CelType::Dyn;
+        }
+        self.error(
+            CheckErrorKind::UndeclaredReference,
+            expression,
+            format!("Undeclared reference to `{}`", name),
+        );
+        CelType::Dyn
+    }
+
+    /**
+     * The host object named by the expression, either `computed`, `device` or one of the declared `namespaces`,
+     * if it is not shadowed by a variable bound by a macro.
+     */
+    fn host_object(&self, expression: &Expression) -> Option<&'a str> {
+        let declarations: &'a Declarations = self.declarations;
+        match expression {
+            Expression::Ident(name) if !self.scopes.iter().any(|(it, _)| it == name.as_str()) => {
+                match HOST_OBJECTS.iter().find(|it| **it == name.as_str()) {
+                    Some(object) => Some(object),
+                    None => declarations.namespaces.get_key_value(name.as_str()).map(|(object, _)| object.as_str()),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn host_properties(&self, object: &str) -> &'a HashMap<String, FunctionSignature> {
+        match object {
+            "computed" => &self.declarations.computed,
+            "device" => &self.declarations.device,
+            namespace => &self.declarations.namespaces[namespace],
+        }
+    }
+
+    fn member(&mut self, expression: &Expression, target: &Expression, member: &Member) -> CelType {
+        if let (Some(object), Member::Attribute(name)) = (self.host_object(target), member) {
+            if let Some(signature) = self.host_properties(object).get(name.as_str()) {
+                if !signature.args.is_empty() {
+                    let message = format!(
+                        "`{}.{}` takes {} argument(s) and has to be called",
+                        object,
+                        name,
+                        signature.args.len()
+                    );
+                    self.error(CheckErrorKind::WrongArity, expression, message);
+                }
+                return signature.result.clone();
+            }
+            // Device and namespace properties can also be passed as a variable of the same name
+            if object == "computed" || !self.declarations.variables.contains_key(object) {
+                self.error(
+                    CheckErrorKind::UndeclaredReference,
+                    expression,
+                    format!("Undeclared reference to `{}.{}`", object, name),
+                );
+                return CelType::Dyn;
+            }
+        }
+        let target_type = self.check(target);
+        match member {
+            Member::Attribute(name) => match target_type {
+                CelType::Object(fields) => match fields.get(name.as_str()) {
+                    Some(field) => field.clone(),
+                    None => {
+                        self.error(
+                            CheckErrorKind::UndeclaredReference,
+                            expression,
+                            format!("No such key: {}", name),
+                        );
+                        CelType::Dyn
+                    }
+                },
+                CelType::Map(value) => *value,
+                CelType::Dyn => CelType::Dyn,
+                other => self.mismatch(expression, format!("Cannot access `{}` on a value of type {}", name, other)),
+            },
+            Member::Index(index) => {
+                let index_type = self.check(index);
+                match (target_type, index_type) {
+                    (CelType::List(item), CelType::Int | CelType::Dyn) => *item,
+                    (CelType::String, CelType::Int | CelType::Dyn) => CelType::String,
+                    (
+                        CelType::Map(value),
+                        CelType::String | CelType::Int | CelType::UInt | CelType::Bool | CelType::Dyn,
+                    ) => *value,
+                    (CelType::Object(_), CelType::String | CelType::Dyn) | (CelType::Dyn, _) => CelType::Dyn,
+                    (target, index) => {
+                        self.mismatch(expression, format!("Cannot index a value of type {} with {}", target, index))
+                    }
+                }
+            }
+            Member::Fields(fields) => {
+                fields.iter().for_each(|(_, it)| {
+                    self.check(it);
+                });
+                CelType::Dyn
+            }
+        }
+    }
+
+    fn arithmetic(&mut self, expression: &Expression, op: &ArithmeticOp, left: CelType, right: CelType) -> CelType {
+        use CelType::*;
+        let result = match (op, &left, &right) {
+            (_, Dyn, _) | (_, _, Dyn) => Some(Dyn),
+            (_, Int, Int) | (_, UInt, UInt) => Some(left.clone()),
+            (_, Float, _) | (_, _, Float) if left.is_numeric() && right.is_numeric() => Some(Float),
+            (ArithmeticOp::Add, String, String) => Some(String),
+            (ArithmeticOp::Add, List(_), List(_)) => match (&left, &right) {
+                (List(l), List(r)) => Some(List(Box::new(CelType::join(*l.clone(), *r.clone())))),
+                _ => None,
+            },
+            (ArithmeticOp::Add, Map(_) | Object(_), Map(_) | Object(_)) => Some(Map(Box::new(Dyn))),
+            (ArithmeticOp::Add | ArithmeticOp::Subtract, Duration, Duration) => Some(Duration),
+            (ArithmeticOp::Add | ArithmeticOp::Subtract, Timestamp, Duration) => Some(Timestamp),
+            (ArithmeticOp::Add, Duration, Timestamp) => Some(Timestamp),
+            (ArithmeticOp::Subtract, Timestamp, Timestamp) => Some(Duration),
+            _ => None,
+        };
+        result.unwrap_or_else(|| {
+            self.mismatch(
+                expression,
+                format!("Unsupported operator {:?} for values of type {} and {}", op, left, right),
+            )
+        })
+    }
+
+    fn relation(&mut self, expression: &Expression, op: &RelationOp, left: CelType, right: CelType) {
+        use CelType::*;
+        let supported = match (op, &left, &right) {
+            (RelationOp::Equals | RelationOp::NotEquals, _, _) => true,
+            (_, Dyn, _) | (_, _, Dyn) => true,
+            (RelationOp::In, String, String) => true,
+            (RelationOp::In, _, List(_) | Map(_) | Object(_)) => true,
+            (RelationOp::In, _, _) => false,
+            (_, l, r) if l.is_numeric() && r.is_numeric() => true,
+            (_, String, String) | (_, Bool, Bool) | (_, Null, Null) => true,
+            (_, Duration, Duration) | (_, Timestamp, Timestamp) => true,
+            _ => false,
+        };
+        if !supported {
+            self.mismatch(expression, format!("Values of type {} and {} cannot be compared", left, right));
+        }
+    }
+
+    fn function_call(
+        &mut self,
+        expression: &Expression,
+        name: &str,
+        target: Option<&Expression>,
+        args: &[Expression],
+    ) -> CelType {
+        // Host properties are only resolved on their object, so they never shadow a function of the same name
+        if let Some(object) = target.and_then(|target| self.host_object(target)) {
+            return self.host_call(expression, object, name, args);
+        }
+        if MACROS.contains(&name) {
+            return self.comprehension(expression, name, target, args);
+        }
+        if let Some(function) = library_function(name, &self.declarations.extensions) {
+            let operands = self.method_operands(target, args);
+            let max = function.operands.len();
+            let min = max - function.optional;
+            if operands.len() < min || operands.len() > max {
+                let count = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+                return self.arity(expression, name, &format!("takes {} operand(s) including its target", count));
+            }
+            for (expected, actual) in function.operands.iter().zip(operands.iter()) {
+                self.expect(expression, name, expected, actual);
+            }
+            return function.result.clone();
+        }
+        match name {
+            "has" => {
+                match (target, args) {
+                    (None, [Expression::Member(inner, member)]) if matches!(member.as_ref(), Member::Attribute(_)) => {
+                        // Only the object is checked, since the attribute itself may not be there
+                        self.check(inner);
+                    }
+                    _ => {
+                        self.error(
+                            CheckErrorKind::WrongArity,
+                            expression,
+                            "`has` takes a single attribute access, i.e. `has(user.name)`".to_string(),
+                        );
+                    }
+                }
+                CelType::Bool
+            }
+            "maybe" => {
+                let Some(target) = target else {
+                    return self.arity(expression, name, "has to be called on a value");
+                };
+                self.check(target);
+                let [left, right] = args else {
+                    return self.arity(expression, name, "takes 2 arguments");
+                };
+                // Errors of the first argument are what `maybe` recovers from
+                let errors = self.errors.len();
+                let left = self.check(left);
+                let failed = self.errors.len() > errors;
+                self.errors.truncate(errors);
+                let right = self.check(right);
+                if failed {
+                    right
+                } else {
+                    left.join(right)
+                }
+            }
+            "size" => match self.function_args(expression, name, target, args, 1).as_deref() {
+                Some([CelType::String | CelType::Bytes | CelType::List(_) | CelType::Map(_) | CelType::Object(_) | CelType::Dyn]) => {
+                    CelType::Int
+                }
+                Some([other]) => self.mismatch(expression, format!("`size` does not support a value of type {}", other)),
+                _ => CelType::Int,
+            },
+            "bytes" | "duration" | "timestamp" => {
+                let result = match name {
+                    "bytes" => CelType::Bytes,
+                    "duration" => CelType::Duration,
+                    _ => CelType::Timestamp,
+                };
+                if let Some([arg]) = self.function_args(expression, name, target, args, 1).as_deref() {
+                    self.expect(expression, name, &CelType::String, arg);
+                }
+                result
+            }
+            "max" => {
+                if let Some(target) = target {
+                    self.check(target);
+                    return self.arity(expression, name, "cannot be called as a method");
+                }
+                let types: Vec<CelType> = args.iter().map(|it| self.check(it)).collect();
+                match types.as_slice() {
+                    [] => self.arity(expression, name, "takes at least 1 argument"),
+                    [CelType::List(item)] => *item.clone(),
+                    _ => types.into_iter().reduce(CelType::join).unwrap_or(CelType::Dyn),
+                }
+            }
+            "contains" | "startsWith" | "endsWith" | "matches" | "string" | "int" | "uint" | "double" => {
+                let operands = self.method_operands(target, args);
+                self.method(expression, name, operands)
+            }
+            _ => {
+                self.method_operands(target, args);
+                self.error(
+                    CheckErrorKind::UnknownFunction,
+                    expression,
+                    format!("Unknown function `{}`", name),
+                );
+                CelType::Dyn
+            }
+        }
+    }
+
+    /**
+     * Checks the target and the arguments of a function receiving its first operand either as the target or as the first argument.
+     */
+    fn method_operands(&mut self, target: Option<&Expression>, args: &[Expression]) -> Vec<CelType> {
+        target
+            .into_iter()
+            .chain(args)
+            .map(|it| self.check(it))
+            .collect()
+    }
+
+    fn method(&mut self, expression: &Expression, name: &str, operands: Vec<CelType>) -> CelType {
+        use CelType::*;
+        let (arity, result) = match name {
+            "string" => (1, String),
+            "int" => (1, Int),
+            "uint" => (1, UInt),
+            "double" => (1, Float),
+            _ => (2, Bool),
+        };
+        if operands.len() != arity {
+            return self.arity(expression, name, &format!("takes {} operand(s) including its target", arity));
+        }
+        match (name, &operands[..]) {
+            ("contains", [List(_) | Map(_) | Object(_) | Dyn, _]) => {}
+            ("contains", [String, String | Dyn] | [Bytes, Bytes | Dyn]) => {}
+            ("startsWith" | "endsWith" | "matches", [this, arg]) => {
+                self.expect(expression, name, &String, this);
+                self.expect(expression, name, &String, arg);
+            }
+            ("contains", [this, arg]) => {
+                self.mismatch(
+                    expression,
+                    format!("`contains` does not support values of type {} and {}", this, arg),
+                );
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /**
+     * Checks the arguments of a function which cannot be called as a method.
+     */
+    fn function_args(
+        &mut self,
+        expression: &Expression,
+        name: &str,
+        target: Option<&Expression>,
+        args: &[Expression],
+        arity: usize,
+    ) -> Option<Vec<CelType>> {
+        if let Some(target) = target {
+            self.check(target);
+            self.arity(expression, name, "cannot be called as a method");
+            return None;
+        }
+        let types: Vec<CelType> = args.iter().map(|it| self.check(it)).collect();
+        if types.len() != arity {
+            self.arity(expression, name, &format!("takes {} argument(s) but {} were given", arity, types.len()));
+            return None;
+        }
+        Some(types)
+    }
+
+    fn comprehension(
+        &mut self,
+        expression: &Expression,
+        name: &str,
+        target: Option<&Expression>,
+        args: &[Expression],
+    ) -> CelType {
+        let (Some(target), [Expression::Ident(variable), body]) = (target, args) else {
+            return self.arity(expression, name, "takes a variable and an expression, i.e. `list.map(x, x * 2)`");
+        };
+        let target_type = self.check(target);
+        let item = match (&target_type, name) {
+            (CelType::List(item), _) => *item.clone(),
+            (CelType::Map(_) | CelType::Object(_), "map") => {
+                self.mismatch(expression, "`map` can only be applied to a list".to_string());
+                CelType::Dyn
+            }
+            (CelType::Map(_) | CelType::Object(_), _) => CelType::Dyn,
+            (CelType::Dyn, _) => CelType::Dyn,
+            (other, _) => {
+                self.mismatch(expression, format!("`{}` cannot be applied to a value of type {}", name, other));
+                CelType::Dyn
+            }
+        };
+        self.scopes.push((variable.to_string(), item));
+        let body = self.check(body);
+        self.scopes.pop();
+        match name {
+            "map" => CelType::List(Box::new(body)),
+            "filter" => match target_type {
+                CelType::List(_) => target_type,
+                _ => CelType::Dyn,
+            },
+            _ => CelType::Bool,
+        }
+    }
+
+    fn host_call(&mut self, expression: &Expression, object: &str, name: &str, args: &[Expression]) -> CelType {
+        let types: Vec<CelType> = args.iter().map(|it| self.check(it)).collect();
+        let Some(signature) = self.host_properties(object).get(name).cloned() else {
+            self.error(
+                CheckErrorKind::UnknownFunction,
+                expression,
+                format!("`{}.{}` is not declared", object, name),
+            );
+            return CelType::Dyn;
+        };
+        if types.len() != signature.args.len() {
+            let message = format!(
+                "`{}.{}` takes {} argument(s) but {} were given",
+                object,
+                name,
+                signature.args.len(),
+                types.len()
+            );
+            self.error(CheckErrorKind::WrongArity, expression, message);
+        } else {
+            for (expected, actual) in signature.args.iter().zip(types.iter()) {
+                self.expect(expression, name, expected, actual);
+            }
+        }
+        signature.result
+    }
+
+    fn expect(&mut self, expression: &Expression, function: &str, expected: &CelType, actual: &CelType) {
+        if !expected.accepts(actual) {
+            self.mismatch(
+                expression,
+                format!("`{}` expects a value of type {} but got {}", function, expected, actual),
+            );
+        }
+    }
+
+    fn arity(&mut self, expression: &Expression, function: &str, message: &str) -> CelType {
+        self.error(CheckErrorKind::WrongArity, expression, format!("`{}` {}", function, message));
+        CelType::Dyn
+    }
+
+    fn mismatch(&mut self, expression: &Expression, message: String) -> CelType {
+        self.error(CheckErrorKind::TypeMismatch, expression, message);
+        CelType::Dyn
+    }
+
+    fn error(&mut self, kind: CheckErrorKind, expression: &Expression, message: String) {
+        self.errors.push(CheckError {
+            kind,
+            message,
+            expression: printer::print(expression).unwrap_or_default(),
+        });
+    }
+}
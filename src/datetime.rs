@@ -0,0 +1,148 @@
+use crate::strings::operands;
+use cel_interpreter::extractors::This;
+use cel_interpreter::{Context, ExecutionError, FunctionContext, Value};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Offset, Timelike, Utc};
+
+type Result<T> = std::result::Result<T, ExecutionError>;
+
+/**
+ * Adds the functions reading the calendar fields of timestamps and the length of durations.
+ * Fields of timestamps are read in UTC unless a time zone is given, i.e. `ts.getHours("+02:00")`.
+ * Like in the CEL specification, months, days of the month and days of the year start at `0`,
+ * except for `getDate` which starts at `1`, and days of the week start at `0` for Sunday.
+ * `now()` is added separately as it depends on the execution context, see `now`.
+ * @param ctx The context to add the functions to
+ */
+pub(crate) fn add_functions(ctx: &mut Context) {
+    ctx.add_function("getFullYear", |ftx: &FunctionContext| field(ftx, |it| it.year() as i64, None));
+    ctx.add_function("getMonth", |ftx: &FunctionContext| field(ftx, |it| it.month0() as i64, None));
+    ctx.add_function("getDate", |ftx: &FunctionContext| field(ftx, |it| it.day() as i64, None));
+    ctx.add_function("getDayOfMonth", |ftx: &FunctionContext| field(ftx, |it| it.day0() as i64, None));
+    ctx.add_function("getDayOfYear", |ftx: &FunctionContext| field(ftx, |it| it.ordinal0() as i64, None));
+    ctx.add_function("getDayOfWeek", |ftx: &FunctionContext| {
+        field(ftx, |it| it.weekday().num_days_from_sunday() as i64, None)
+    });
+    ctx.add_function("getHours", |ftx: &FunctionContext| {
+        field(ftx, |it| it.hour() as i64, Some(Duration::num_hours))
+    });
+    ctx.add_function("getMinutes", |ftx: &FunctionContext| {
+        field(ftx, |it| it.minute() as i64, Some(Duration::num_minutes))
+    });
+    ctx.add_function("getSeconds", |ftx: &FunctionContext| {
+        field(ftx, |it| it.second() as i64, Some(Duration::num_seconds))
+    });
+    ctx.add_function("getMilliseconds", |ftx: &FunctionContext| {
+        field(ftx, |it| (it.nanosecond() / 1_000_000 % 1000) as i64, Some(Duration::num_milliseconds))
+    });
+    ctx.add_function("daysBetween", days_between);
+}
+
+/**
+ * Reads a field of a timestamp in the time zone given as argument, or the total length of a duration
+ * in the field's unit if the field applies to durations, i.e. `duration("90m").getHours() == 1`.
+ */
+fn field(
+    ftx: &FunctionContext,
+    of_timestamp: fn(&DateTime<FixedOffset>) -> i64,
+    of_duration: Option<fn(&Duration) -> i64>,
+) -> Result<Value> {
+    let operands = operands(ftx, 1, 2)?;
+    match (&operands[0], operands.get(1), of_duration) {
+        (Value::Timestamp(timestamp), zone, _) => {
+            let offset = match zone {
+                Some(Value::String(zone)) => time_zone(ftx, zone)?,
+                Some(other) => return Err(ftx.error(format!("Expected a time zone, got {:?}", other))),
+                None => Utc.fix(),
+            };
+            Ok(Value::Int(of_timestamp(&timestamp.with_timezone(&offset))))
+        }
+        (Value::Duration(duration), None, Some(of_duration)) => Ok(Value::Int(of_duration(duration))),
+        (other, _, Some(_)) => Err(ftx.error(format!("Expected a timestamp or a duration, got {:?}", other))),
+        (other, _, None) => Err(ftx.error(format!("Expected a timestamp, got {:?}", other))),
+    }
+}
+
+/**
+ * Parses a time zone, which is either `UTC` or a fixed offset from it such as `+05:30`, `-0800` or `UTC+2`.
+ * Named zones such as `Europe/Paris` need a time zone database and are not supported,
+ * so hosts are expected to pass the current offset of the device instead.
+ */
+fn time_zone(ftx: &FunctionContext, zone: &str) -> Result<FixedOffset> {
+    let invalid = || {
+        ftx.error(format!(
+            "Unsupported time zone {}, expected UTC or a fixed offset such as +05:30",
+            zone
+        ))
+    };
+    let offset = ["UTC", "GMT"]
+        .iter()
+        .find_map(|prefix| zone.strip_prefix(prefix))
+        .unwrap_or(zone);
+    if offset.is_empty() || offset == "Z" {
+        return Ok(Utc.fix());
+    }
+    let (sign, offset) = match (offset.strip_prefix('+'), offset.strip_prefix('-')) {
+        (Some(rest), _) => (1, rest),
+        (_, Some(rest)) => (-1, rest),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some(parts) => parts,
+        // Only split ASCII offsets by bytes, other characters are rejected as digits below
+        None if offset.len() == 4 && offset.is_ascii() => offset.split_at(2),
+        None => (offset, "00"),
+    };
+    let number = |it: &str| {
+        if (1..=2).contains(&it.len()) && it.bytes().all(|c| c.is_ascii_digit()) {
+            it.parse::<i32>().ok()
+        } else {
+            None
+        }
+    };
+    match (number(hours), number(minutes)) {
+        (Some(hours), Some(minutes)) if minutes < 60 => {
+            FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/**
+ * The number of whole days elapsed from the first timestamp to the second one, negative if the second one is earlier,
+ * i.e. `daysBetween(device.installDate, now()) >= 3`.
+ */
+pub fn days_between(This(from): This<DateTime<FixedOffset>>, to: DateTime<FixedOffset>) -> i64 {
+    (to - from).num_days()
+}
+
+/**
+ * The current time of the evaluation, i.e. `now() - device.installDate > duration("72h")`.
+ * It is read once per evaluation, from the `now` of the execution context if set, so every call returns the same value.
+ * @param now The current time, if known
+ */
+pub(crate) fn now(ftx: &FunctionContext, now: Option<DateTime<FixedOffset>>) -> Result<Value> {
+    if !ftx.args.is_empty() || ftx.this.is_some() {
+        return Err(ExecutionError::invalid_argument_count(0, ftx.args.len() + ftx.this.iter().count()));
+    }
+    now.map(Value::Timestamp)
+        .ok_or_else(|| ftx.error("The current time is not available, set `now` in the execution context"))
+}
+
+/**
+ * The current time of the system, used when the execution context does not set `now`.
+ * WebAssembly has no system clock, so the host must always set it there.
+ */
+pub(crate) fn current_time() -> Option<DateTime<FixedOffset>> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use chrono::TimeZone;
+        let elapsed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+        Utc.timestamp_opt(elapsed.as_secs() as i64, elapsed.subsec_nanos())
+            .single()
+            .map(|it| it.fixed_offset())
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        None
+    }
+}
@@ -0,0 +1,232 @@
+use cel_interpreter::ExecutionError;
+use lalrpop_util::ParseError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/**
+ * Structured error returned in the `Err` side of every JSON `Result` produced by the library.
+ * It is serialized with a `kind` discriminator, so the host can branch on the kind of the error
+ * instead of matching on the message, i.e. `{"kind":"undeclared_reference","message":"...","identifier":"foo"}`.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "kind")]
+pub enum EvaluationError {
+    /// The expression is not valid CEL. Line and column are 1-based and point to the start of the span.
+    #[serde(rename = "parse")]
+    Parse {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        span: Option<Span>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        column: Option<usize>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        expected: Vec<String>,
+    },
+    /// The JSON passed in by the host does not match the expected model.
+    #[serde(rename = "deserialization")]
+    Deserialization { message: String },
+    /// The expression references a variable, key or function that does not exist.
+    #[serde(rename = "undeclared_reference")]
+    UndeclaredReference { message: String, identifier: String },
+    /// A function failed during execution or was called with the wrong arguments.
+    #[serde(rename = "function_error")]
+    FunctionError {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        function: Option<String>,
+    },
+    /// The host context failed to resolve a property.
+    #[serde(rename = "host_error")]
+    HostError { message: String, property: String },
+    /// An operation was applied to values of a type it does not support.
+    #[serde(rename = "type_mismatch")]
+    TypeMismatch { message: String },
+    /// The evaluation exceeded one of the configured `limits`, named by its field, i.e. `max_steps`.
+    #[serde(rename = "limit_exceeded")]
+    LimitExceeded { message: String, limit: String },
+    /// The expression contains a node the operation does not support.
+    #[serde(rename = "unsupported")]
+    Unsupported { message: String },
+}
+
+/**
+ * Byte offsets of the part of the expression source an error refers to.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl EvaluationError {
+    /**
+     * Creates a parse error for the given expression from the error returned by the parser,
+     * resolving where in the source the error happened and which tokens were expected there.
+     * @param expression The expression that failed to parse
+     * @param err The error returned by the parser
+     */
+    pub(crate) fn parse<T: fmt::Display, E: fmt::Display>(
+        expression: &str,
+        err: ParseError<usize, T, E>,
+    ) -> Self {
+        let (span, expected) = match &err {
+            ParseError::InvalidToken { location } => (
+                Some(Span {
+                    start: *location,
+                    end: *location + 1,
+                }),
+                vec![],
+            ),
+            ParseError::UnrecognizedEOF { location, expected } => (
+                Some(Span {
+                    start: *location,
+                    end: *location,
+                }),
+                expected.clone(),
+            ),
+            ParseError::UnrecognizedToken { token, expected } => (
+                Some(Span {
+                    start: token.0,
+                    end: token.2,
+                }),
+                expected.clone(),
+            ),
+            ParseError::ExtraToken { token } => (
+                Some(Span {
+                    start: token.0,
+                    end: token.2,
+                }),
+                vec![],
+            ),
+            ParseError::User { .. } => (None, vec![]),
+        };
+        let position = span.as_ref().map(|span| line_and_column(expression, span.start));
+        let mut expected_tokens: Vec<String> = vec![];
+        for token in expected.iter().map(|token| describe_token(token)) {
+            if !expected_tokens.contains(&token) {
+                expected_tokens.push(token);
+            }
+        }
+        EvaluationError::Parse {
+            message: err.to_string(),
+            span,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            expected: expected_tokens,
+        }
+    }
+
+    /**
+     * Creates a parse error for a literal the parser cannot read, such as an out of range integer.
+     * @param expression The expression containing the literal
+     * @param start The byte offset where the literal starts
+     * @param end The byte offset following the literal
+     * @param message The description of the problem
+     */
+    pub(crate) fn invalid_literal(expression: &str, start: usize, end: usize, message: String) -> Self {
+        let (line, column) = line_and_column(expression, start);
+        EvaluationError::Parse {
+            message,
+            span: Some(Span { start, end }),
+            line: Some(line),
+            column: Some(column),
+            expected: vec![],
+        }
+    }
+
+    pub(crate) fn deserialization(err: serde_json::Error) -> Self {
+        EvaluationError::Deserialization {
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            EvaluationError::Parse { message, .. }
+            | EvaluationError::Deserialization { message }
+            | EvaluationError::UndeclaredReference { message, .. }
+            | EvaluationError::FunctionError { message, .. }
+            | EvaluationError::HostError { message, .. }
+            | EvaluationError::TypeMismatch { message }
+            | EvaluationError::LimitExceeded { message, .. }
+            | EvaluationError::Unsupported { message } => message,
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl From<ExecutionError> for EvaluationError {
+    fn from(err: ExecutionError) -> Self {
+        let message = err.to_string();
+        match err {
+            ExecutionError::UndeclaredReference(name) | ExecutionError::NoSuchKey(name) => {
+                EvaluationError::UndeclaredReference {
+                    message,
+                    identifier: (*name).clone(),
+                }
+            }
+            ExecutionError::FunctionError { function, .. } => EvaluationError::FunctionError {
+                message,
+                function: Some(function),
+            },
+            ExecutionError::NotSupportedAsMethod { method, .. } => EvaluationError::FunctionError {
+                message,
+                function: Some(method),
+            },
+            ExecutionError::InvalidArgumentCount { .. }
+            | ExecutionError::MissingArgumentOrTarget
+            | ExecutionError::UnsupportedFunctionCallIdentifierType(_) => {
+                EvaluationError::FunctionError {
+                    message,
+                    function: None,
+                }
+            }
+            _ => EvaluationError::TypeMismatch { message },
+        }
+    }
+}
+
+/**
+ * Converts a byte offset in the source into a 1-based line and column pair.
+ */
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = source.get(..offset).unwrap_or(source);
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .map(|it| it.chars().count())
+        .unwrap_or(0)
+        + 1;
+    (line, column)
+}
+
+/**
+ * The parser reports expected tokens by their grammar names, which are either quoted literals
+ * such as `"&&"` or raw regular expressions. Turn those into something that can be shown to a user.
+ */
+fn describe_token(token: &str) -> String {
+    if token.starts_with("r#") {
+        let name = if token.contains("[_a-zA-Z]") {
+            "identifier"
+        } else if token.contains("[bB]") {
+            "bytes"
+        } else if token.contains("[uU]") {
+            "uint"
+        } else if token.contains("\\.[0-9]") || token.contains("[eE]") {
+            "float"
+        } else if token.contains("[0-9") {
+            "int"
+        } else {
+            "string"
+        };
+        name.to_string()
+    } else {
+        token.trim_matches('"').to_string()
+    }
+}
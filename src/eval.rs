@@ -0,0 +1,563 @@
+use crate::analysis::MACROS;
+use crate::error::EvaluationError;
+use crate::models::{EvaluationLimits, TraceNode};
+use crate::printer;
+use crate::DisplayableValue;
+use cel_interpreter::objects::{Key, ValueType};
+use cel_interpreter::{Context, ExecutionError, Expression, Value};
+use cel_parser::{ArithmeticOp, Atom, Member};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/**
+ * Tracks the resources used by an evaluation against its limits.
+ * It is shared between the evaluator and the functions resolving host properties, so when a host function
+ * fails because the budget is exhausted or the host failed, the evaluator can report that instead of the function error.
+ */
+#[derive(Clone, Default)]
+pub(crate) struct Budget {
+    limits: Arc<EvaluationLimits>,
+    steps: Arc<AtomicU64>,
+    host_calls: Arc<AtomicUsize>,
+    exceeded: Arc<Mutex<Option<EvaluationError>>>,
+    // Failures of the host, each with the function error the interpreter knows it as
+    host_errors: Arc<Mutex<Vec<(ExecutionError, EvaluationError)>>>,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Option<EvaluationLimits>) -> Self {
+        Budget {
+            limits: Arc::new(limits.unwrap_or_default()),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * Counts a call to the host context, failing if it would exceed the `max_host_calls` limit.
+     */
+    pub(crate) fn host_call(&self) -> Result<(), EvaluationError> {
+        let calls = self.host_calls.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.limits.max_host_calls {
+            Some(max) if calls > max => Err(self.exceed(
+                "max_host_calls",
+                format!("Evaluation exceeded the limit of {} host calls", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Reserves up to `requested` host calls, returning how many of them can be made without exceeding the limit.
+     */
+    pub(crate) fn reserve_host_calls(&self, requested: usize) -> usize {
+        let reserved = match self.limits.max_host_calls {
+            Some(max) => requested.min(max.saturating_sub(self.host_calls.load(Ordering::SeqCst))),
+            None => requested,
+        };
+        self.host_calls.fetch_add(reserved, Ordering::SeqCst);
+        reserved
+    }
+
+    /**
+     * Prepares the budget for running the evaluation again, or for the next rule of a rule set:
+     * its steps start over, while its host calls are kept.
+     */
+    pub(crate) fn restart(&self) {
+        self.steps.store(0, Ordering::Relaxed);
+        if let Ok(mut exceeded) = self.exceeded.lock() {
+            *exceeded = None;
+        }
+        if let Ok(mut host_errors) = self.host_errors.lock() {
+            host_errors.clear();
+        }
+    }
+
+    /**
+     * Reports that the host failed to resolve a property, returning the function error the interpreter propagates,
+     * which the evaluator then reports as the host error.
+     * @param function The name of the host function
+     * @param err The `host_error` naming the property
+     */
+    pub(crate) fn host_error(&self, function: &str, err: EvaluationError) -> ExecutionError {
+        let function_error = ExecutionError::function_error(function, err.to_string());
+        if let Ok(mut host_errors) = self.host_errors.lock() {
+            if !host_errors.iter().any(|(it, _)| *it == function_error) {
+                host_errors.push((function_error.clone(), err));
+            }
+        }
+        function_error
+    }
+
+    /**
+     * Converts an error returned by the interpreter, preferring the exceeded limit if it caused the failure,
+     * and the host error if the failure is the one of a host function.
+     */
+    pub(crate) fn error(&self, err: ExecutionError) -> EvaluationError {
+        self.exceeded
+            .lock()
+            .ok()
+            .and_then(|exceeded| exceeded.clone())
+            .or_else(|| {
+                let host_errors = self.host_errors.lock().ok()?;
+                host_errors.iter().find(|(it, _)| *it == err).map(|(_, host_error)| host_error.clone())
+            })
+            .unwrap_or_else(|| err.into())
+    }
+
+    fn exceed(&self, limit: &str, message: String) -> EvaluationError {
+        let err = EvaluationError::LimitExceeded {
+            message,
+            limit: limit.to_string(),
+        };
+        if let Ok(mut exceeded) = self.exceeded.lock() {
+            exceeded.get_or_insert(err.clone());
+        }
+        err
+    }
+
+    fn step(&self) -> Result<(), EvaluationError> {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.limits.max_steps {
+            Some(max) if steps > max => Err(self.exceed(
+                "max_steps",
+                format!("Evaluation exceeded the limit of {} steps", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_depth(&self, expression: &Expression) -> Result<(), EvaluationError> {
+        match self.limits.max_depth {
+            Some(max) if exceeds_depth(expression, max) => Err(self.exceed(
+                "max_depth",
+                format!("Expression exceeds the maximum depth of {}", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_size(&self, value: &Value) -> Result<(), EvaluationError> {
+        let (limit, max, size) = match value {
+            Value::String(it) => ("max_string_length", self.limits.max_string_length, it.len()),
+            Value::Bytes(it) => ("max_string_length", self.limits.max_string_length, it.len()),
+            Value::List(it) => ("max_list_size", self.limits.max_list_size, it.len()),
+            Value::Map(it) => ("max_list_size", self.limits.max_list_size, it.map.len()),
+            _ => return Ok(()),
+        };
+        match max {
+            Some(max) if size > max => Err(self.exceed(
+                limit,
+                format!("Evaluation produced a value of size {}, exceeding the limit of {}", size, max),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * Evaluates the expression within the given budget. The evaluation follows the interpreter's own semantics,
+ * but walks the tree itself so that every step, including each iteration of the macros, is counted.
+ * @param expression The expression to evaluate
+ * @param ctx The context to evaluate the expression in
+ * @param budget The budget the evaluation is limited by
+ * @param trace If set, the trace of the evaluated expression is pushed into it
+ */
+pub(crate) fn evaluate(
+    expression: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    trace: Option<&mut Vec<TraceNode>>,
+) -> Result<Value, EvaluationError> {
+    budget.check_depth(expression)?;
+    resolve(expression, ctx, budget, trace)
+}
+
+fn resolve(
+    expression: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    trace: Option<&mut Vec<TraceNode>>,
+) -> Result<Value, EvaluationError> {
+    match trace {
+        None => resolve_node(expression, ctx, budget, None),
+        Some(parent) => {
+            let mut children = vec![];
+            let result = resolve_node(expression, ctx, budget, Some(&mut children));
+            parent.push(TraceNode {
+                value: result.as_ref().ok().map(|it| DisplayableValue(it.clone()).to_passable()),
+                error: result.as_ref().err().cloned(),
+                children,
+                ..trace_node(expression)
+            });
+            result
+        }
+    }
+}
+
+fn resolve_node(
+    expression: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    mut trace: Option<&mut Vec<TraceNode>>,
+) -> Result<Value, EvaluationError> {
+    budget.step()?;
+    let value = match expression {
+        Expression::Atom(_) | Expression::Ident(_) => {
+            return ctx.resolve(expression).map_err(|err| budget.error(err));
+        }
+        Expression::Ternary(condition, if_true, if_false) => {
+            let (taken, skipped, skipped_first) = if to_bool(&resolve(condition, ctx, budget, trace.as_deref_mut())?) {
+                (if_true, if_false, false)
+            } else {
+                (if_false, if_true, true)
+            };
+            if skipped_first {
+                skip(skipped, trace.as_deref_mut());
+            }
+            let value = resolve(taken, ctx, budget, trace.as_deref_mut());
+            if !skipped_first {
+                skip(skipped, trace);
+            }
+            return value;
+        }
+        Expression::Or(left, right) => {
+            let left = resolve(left, ctx, budget, trace.as_deref_mut())?;
+            return if to_bool(&left) {
+                skip(right, trace);
+                Ok(left)
+            } else {
+                resolve(right, ctx, budget, trace)
+            };
+        }
+        Expression::Member(target, member) => {
+            let expression = match lazy_attribute(ctx, target, member) {
+                Some(function) => &Expression::FunctionCall(Box::new(Expression::Ident(function)), None, vec![]),
+                None => expression,
+            };
+            return delegate(expression, ctx, budget, trace);
+        }
+        // Host properties named like a macro or `maybe` are called like any other host property
+        Expression::FunctionCall(function, Some(target), _)
+            if matches!(function.as_ref(), Expression::Ident(name) if host_function(ctx, name, target).is_some()) =>
+        {
+            delegate(expression, ctx, budget, trace)?
+        }
+        Expression::FunctionCall(function, target, args) => match (function.as_ref(), receiver(function, target, args)) {
+            (Expression::Ident(name), Some((target, [Expression::Ident(variable), body])))
+                if MACROS.contains(&name.as_str()) =>
+            {
+                comprehension(name, target, variable, body, ctx, budget, trace)?
+            }
+            (Expression::Ident(name), Some((target, [left, right]))) if name.as_str() == "maybe" => {
+                resolve(target, ctx, budget, trace.as_deref_mut())?;
+                return match resolve(left, ctx, budget, trace.as_deref_mut()) {
+                    Err(err @ EvaluationError::LimitExceeded { .. }) => Err(err),
+                    Err(_) => resolve(right, ctx, budget, trace),
+                    value => {
+                        skip(right, trace);
+                        value
+                    }
+                };
+            }
+            // The interpreter expects the unevaluated arguments to be there, and panics if they are not
+            (Expression::Ident(name), receiver)
+                if (name.as_str() == "maybe" || MACROS.contains(&name.as_str()))
+                    && receiver.is_none_or(|(_, args)| args.len() != 2) =>
+            {
+                let actual = receiver.map_or(args.len(), |(_, args)| args.len());
+                return Err(ExecutionError::invalid_argument_count(2, actual).into());
+            }
+            (Expression::Ident(name), _) if name.as_str() == "has" && args.len() != 1 => {
+                return Err(ExecutionError::invalid_argument_count(1, args.len()).into());
+            }
+            // Lazy attributes are declared, so they exist before the host resolves them
+            (Expression::Ident(name), None)
+                if name.as_str() == "has"
+                    && matches!(args.as_slice(), [Expression::Member(target, member)]
+                        if lazy_attribute(ctx, target, member).is_some()) =>
+            {
+                Value::Bool(true)
+            }
+            _ => delegate(expression, ctx, budget, trace)?,
+        },
+        _ => delegate(expression, ctx, budget, trace)?,
+    };
+    budget.check_size(&value)?;
+    Ok(value)
+}
+
+/**
+ * The target of a call to a macro or `maybe` and its remaining arguments, which the interpreter takes unevaluated.
+ * Like in the interpreter, the target can also be passed as first argument, i.e. `maybe(x, a, b)` or `map(list, x, x * 2)`.
+ */
+fn receiver<'a>(
+    function: &Expression,
+    target: &'a Option<Box<Expression>>,
+    args: &'a [Expression],
+) -> Option<(&'a Expression, &'a [Expression])> {
+    match (function, target, args) {
+        (_, Some(target), args) => Some((target, args)),
+        (Expression::Ident(name), None, [target, args @ ..])
+            if name.as_str() == "maybe" || MACROS.contains(&name.as_str()) =>
+        {
+            Some((target, args))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Evaluates the direct children of the expression within the budget, then lets the interpreter
+ * apply the expression itself to their values, which are bound to temporary variables in an inner scope.
+ */
+fn delegate(
+    expression: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    mut trace: Option<&mut Vec<TraceNode>>,
+) -> Result<Value, EvaluationError> {
+    let mut scope = ctx.new_inner_scope();
+    let mut bound = 0;
+    let mut bind = |expression: &Expression| -> Result<Box<Expression>, EvaluationError> {
+        let value = resolve(expression, ctx, budget, trace.as_deref_mut())?;
+        // Not a valid identifier, so it cannot shadow any variable of the expression
+        let name = format!("@{}", bound);
+        bound += 1;
+        scope.add_variable_from_value(name.clone(), value);
+        Ok(Box::new(Expression::Ident(Arc::new(name))))
+    };
+    let shallow = match expression {
+        Expression::Arithmetic(left, op, right) => Expression::Arithmetic(bind(left)?, op.clone(), bind(right)?),
+        Expression::Relation(left, op, right) => Expression::Relation(bind(left)?, op.clone(), bind(right)?),
+        Expression::And(left, right) => Expression::And(bind(left)?, bind(right)?),
+        Expression::Unary(op, operand) => Expression::Unary(op.clone(), bind(operand)?),
+        Expression::Member(target, member) => {
+            let target = bind(target)?;
+            let member = match member.as_ref() {
+                Member::Index(index) => Member::Index(bind(index)?),
+                member => member.clone(),
+            };
+            Expression::Member(target, Box::new(member))
+        }
+        Expression::List(items) => Expression::List(
+            items.iter().map(|it| bind(it).map(|it| *it)).collect::<Result<_, _>>()?,
+        ),
+        Expression::Map(entries) => Expression::Map(
+            entries
+                .iter()
+                .map(|(key, value)| Ok((*bind(key)?, *bind(value)?)))
+                .collect::<Result<_, EvaluationError>>()?,
+        ),
+        // `has` needs the unevaluated member expression to check if it exists
+        Expression::FunctionCall(function, target, args) => match function.as_ref() {
+            Expression::Ident(name) if name.as_str() != "has" => {
+                let host_function = target.as_ref().and_then(|target| host_function(ctx, name, target));
+                if host_function.is_none() && !has_function(ctx, name) {
+                    return Err(ExecutionError::UndeclaredReference(name.clone()).into());
+                }
+                // The host object is only the namespace of its properties, so it is not passed to them
+                let target = match host_function {
+                    Some(_) => None,
+                    None => target.as_ref().map(|it| bind(it)).transpose()?,
+                };
+                let args = args.iter().map(|it| bind(it).map(|it| *it)).collect::<Result<_, _>>()?;
+                let function = host_function.map(|it| Box::new(Expression::Ident(it))).unwrap_or(function.clone());
+                Expression::FunctionCall(function, target, args)
+            }
+            _ => return ctx.resolve(expression).map_err(|err| budget.error(err)),
+        },
+        _ => return ctx.resolve(expression).map_err(|err| budget.error(err)),
+    };
+    if let Expression::Arithmetic(left, op, right) = &shallow {
+        let operand = |it: &Expression| scope.resolve(it).map_err(|err| budget.error(err));
+        check_division(op, &operand(left)?, &operand(right)?)?;
+    }
+    scope.resolve(&shallow).map_err(|err| budget.error(err))
+}
+
+/**
+ * Checks the operands of an integer division or remainder, on which the interpreter panics
+ * if the divisor is zero or the result overflows, i.e. `-9223372036854775808 / -1`.
+ */
+fn check_division(op: &ArithmeticOp, left: &Value, right: &Value) -> Result<(), EvaluationError> {
+    let function = match op {
+        ArithmeticOp::Divide => "div",
+        ArithmeticOp::Modulus => "rem",
+        _ => return Ok(()),
+    };
+    let message = match (left, right) {
+        (Value::Int(_), Value::Int(0)) | (Value::UInt(_), Value::UInt(0)) => "Division by zero",
+        (Value::Int(i64::MIN), Value::Int(-1)) => "Integer overflow",
+        _ => return Ok(()),
+    };
+    Err(ExecutionError::function_error(function, message).into())
+}
+
+/**
+ * Evaluates the macros binding a variable for each item of the target, i.e. `list.map(x, x * 2)`.
+ * Mirrors the implementation of the macros in the interpreter.
+ */
+fn comprehension(
+    name: &str,
+    target: &Expression,
+    variable: &str,
+    body: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    mut trace: Option<&mut Vec<TraceNode>>,
+) -> Result<Value, EvaluationError> {
+    let target = resolve(target, ctx, budget, trace.as_deref_mut())?;
+    let items: Vec<Value> = match (&target, name) {
+        (Value::List(items), _) => items.iter().cloned().collect(),
+        (Value::Map(map), "all" | "exists" | "exists_one") => map.map.keys().map(Value::from).collect(),
+        _ => return Err(target.error_expected_type(ValueType::List).into()),
+    };
+    let mut scope = ctx.new_inner_scope();
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        scope.add_variable_from_value(variable, item.clone());
+        let result = resolve(body, &scope, budget, trace.as_deref_mut())?;
+        match (name, result) {
+            ("map", result) => results.push(result),
+            ("filter", Value::Bool(true)) => results.push(item),
+            ("all", Value::Bool(false)) => return Ok(Value::Bool(false)),
+            ("exists", Value::Bool(true)) => return Ok(Value::Bool(true)),
+            ("exists_one", Value::Bool(true)) => {
+                if !results.is_empty() {
+                    return Ok(Value::Bool(false));
+                }
+                results.push(item);
+            }
+            _ => {}
+        }
+    }
+    Ok(match name {
+        "map" | "filter" => Value::List(Arc::new(results)),
+        "all" => Value::Bool(true),
+        "exists" => Value::Bool(false),
+        _ => Value::Bool(results.len() == 1),
+    })
+}
+
+/**
+ * Checks if a function is registered in the context, without calling it.
+ * Accessing an attribute of `null` resolves to the function of the same name if there is one.
+ */
+pub(crate) fn has_function(ctx: &Context, name: &Arc<String>) -> bool {
+    let lookup = Expression::Member(
+        Box::new(Expression::Atom(cel_parser::Atom::Null)),
+        Box::new(Member::Attribute(name.clone())),
+    );
+    ctx.resolve(&lookup).is_ok()
+}
+
+/**
+ * Finds the function resolving a host property called on its object, i.e. `computed.daysSinceEvent()`.
+ * Host properties are registered under their object and name, so they never shadow the function of the same name.
+ * @return The name the function is registered under, if the target is a host object declaring the property
+ */
+pub(crate) fn host_function(ctx: &Context, name: &Arc<String>, target: &Expression) -> Option<Arc<String>> {
+    match target {
+        Expression::Ident(object) => {
+            let function = Arc::new(format!("{}.{}", object, name));
+            has_function(ctx, &function).then_some(function)
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Finds the function resolving a lazy attribute of a host object, i.e. `device.osVersion` or `device['osVersion']`.
+ * A value given for the attribute in the variable of the same name is used instead, so the host is not called for it.
+ * @return The name the function is registered under, if the target is a host object declaring the attribute
+ */
+pub(crate) fn lazy_attribute(ctx: &Context, target: &Expression, member: &Member) -> Option<Arc<String>> {
+    let name = match member {
+        Member::Attribute(name) => name,
+        Member::Index(index) => match index.as_ref() {
+            Expression::Atom(Atom::String(name)) => name,
+            _ => return None,
+        },
+        Member::Fields(_) => return None,
+    };
+    match target {
+        Expression::Ident(object) => {
+            let function = Arc::new(format!("{}.{}", object, name));
+            // Indexing a map with a missing key resolves to `null`, so the key is looked up instead
+            let given = || match ctx.resolve(target) {
+                Ok(Value::Map(values)) => values.map.contains_key(&Key::String(name.clone())),
+                _ => false,
+            };
+            (has_function(ctx, &function) && !given()).then_some(function)
+        }
+        _ => None,
+    }
+}
+
+fn exceeds_depth(expression: &Expression, max: usize) -> bool {
+    if max == 0 {
+        return true;
+    }
+    let mut exceeds = false;
+    crate::analysis::walk_children(expression, &mut |child| {
+        exceeds = exceeds || exceeds_depth(child, max - 1);
+    });
+    exceeds
+}
+
+/**
+ * Truthiness of a value, as used by the interpreter for the logical operators.
+ */
+pub(crate) fn to_bool(value: &Value) -> bool {
+    match value {
+        Value::List(v) => !v.is_empty(),
+        Value::Map(v) => !v.map.is_empty(),
+        Value::Int(v) => *v != 0,
+        Value::UInt(v) => *v != 0,
+        Value::Float(v) => *v != 0.0,
+        Value::String(v) => !v.is_empty(),
+        Value::Bytes(v) => !v.is_empty(),
+        Value::Bool(v) => *v,
+        Value::Null => false,
+        Value::Duration(v) => v.num_nanoseconds().map(|n| n != 0).unwrap_or(false),
+        Value::Timestamp(v) => v.timestamp_nanos_opt().unwrap_or_default() > 0,
+        Value::Function(_, _) => false,
+    }
+}
+
+/**
+ * Adds a node which was not evaluated to the trace.
+ */
+fn skip(expression: &Expression, trace: Option<&mut Vec<TraceNode>>) {
+    if let Some(trace) = trace {
+        trace.push(TraceNode {
+            skipped: true,
+            ..trace_node(expression)
+        });
+    }
+}
+
+fn trace_node(expression: &Expression) -> TraceNode {
+    let kind = match expression {
+        Expression::Arithmetic(..) => "Arithmetic",
+        Expression::Relation(..) => "Relation",
+        Expression::Ternary(..) => "Ternary",
+        Expression::Or(..) => "Or",
+        Expression::And(..) => "And",
+        Expression::Unary(..) => "Unary",
+        Expression::Member(..) => "Member",
+        Expression::FunctionCall(..) => "FunctionCall",
+        Expression::List(..) => "List",
+        Expression::Map(..) => "Map",
+        Expression::Atom(..) => "Atom",
+        Expression::Ident(..) => "Ident",
+    };
+    TraceNode {
+        kind: kind.to_string(),
+        expression: printer::print(expression).ok(),
+        value: None,
+        error: None,
+        skipped: false,
+        children: vec![],
+    }
+}
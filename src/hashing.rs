@@ -0,0 +1,105 @@
+use cel_interpreter::extractors::This;
+use cel_interpreter::{ExecutionError, FunctionContext, Value};
+use std::sync::Arc;
+
+/**
+ * 64-bit FNV-1a hash of the UTF-8 bytes of a string.
+ * Implemented here rather than taken from the standard library so it stays identical on every platform and version.
+ */
+pub(crate) fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/**
+ * 32-bit MurmurHash3 (x86 variant) of the UTF-8 bytes of a string.
+ */
+pub(crate) fn murmur3_32(bytes: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mut hash = seed;
+    let chunks = bytes.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0u32, |k, byte| (k << 8) | *byte as u32);
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    hash ^= bytes.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^ (hash >> 16)
+}
+
+/**
+ * The bucket a key falls into for the given salt, from `0` to `buckets - 1`.
+ * It is the MurmurHash3 of `"<salt>:<key>"` modulo the number of buckets, so every salt,
+ * i.e. every experiment, assigns keys to buckets independently from the other ones.
+ */
+fn bucket_of(key: &str, salt: &str, buckets: u64) -> u64 {
+    murmur3_32(format!("{}:{}", salt, key).as_bytes(), 0) as u64 % buckets
+}
+
+/**
+ * Experiments are usually keyed by user ids, which can be either strings or numbers.
+ */
+fn key_string(ftx: &FunctionContext, key: &Value) -> Result<String, ExecutionError> {
+    match key {
+        Value::String(it) => Ok(it.to_string()),
+        Value::Int(it) => Ok(it.to_string()),
+        Value::UInt(it) => Ok(it.to_string()),
+        other => Err(ftx.error(format!("Expected a string or an integer key, got {:?}", other))),
+    }
+}
+
+/**
+ * 64-bit FNV-1a hash of a string, i.e. `fnv1a64("user_1")`.
+ */
+pub fn fnv1a64(This(this): This<Arc<String>>) -> u64 {
+    fnv1a_64(this.as_bytes())
+}
+
+/**
+ * 32-bit MurmurHash3 of a string with a seed of `0`, i.e. `murmur3("user_1")`.
+ */
+pub fn murmur3(This(this): This<Arc<String>>) -> u64 {
+    murmur3_32(this.as_bytes(), 0) as u64
+}
+
+/**
+ * Assigns a key to one of the given number of buckets, i.e. `bucket(user.id, "experiment_42", 100) < 25`.
+ */
+pub fn bucket(ftx: &FunctionContext, key: Value, salt: Arc<String>, buckets: i64) -> Result<i64, ExecutionError> {
+    if buckets <= 0 {
+        return Err(ftx.error(format!("The number of buckets must be positive, got {}", buckets)));
+    }
+    Ok(bucket_of(&key_string(ftx, &key)?, &salt, buckets as u64) as i64)
+}
+
+/**
+ * Checks if a key is part of a rollout to the given percentage of keys, i.e. `percentRollout(user.id, "new_paywall", 12.5)`.
+ * Keys are assigned to one of 10000 buckets, so percentages are precise to two decimals, and a key included in
+ * a rollout stays included when its percentage increases.
+ */
+pub fn percent_rollout(
+    ftx: &FunctionContext,
+    key: Value,
+    salt: Arc<String>,
+    percent: Value,
+) -> Result<bool, ExecutionError> {
+    let percent = match percent {
+        Value::Int(it) => it as f64,
+        Value::UInt(it) => it as f64,
+        Value::Float(it) => it,
+        other => return Err(ftx.error(format!("Expected a number as percentage, got {:?}", other))),
+    };
+    let bucket = bucket_of(&key_string(ftx, &key)?, &salt, 10000);
+    Ok((bucket as f64) < percent * 100.0)
+}
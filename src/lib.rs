@@ -1,18 +1,43 @@
 #[cfg(not(target_arch = "wasm32"))]
 uniffi::include_scaffolding!("cel");
+mod analysis;
 mod ast;
+mod checker;
+mod datetime;
+mod error;
+mod eval;
+mod hashing;
+mod library;
+mod literals;
 mod models;
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+mod optimizer;
+mod partial;
+mod printer;
+mod semver;
+mod strings;
+mod timeout;
 
 use crate::ast::{ASTExecutionContext, JSONExpression};
+use crate::error::EvaluationError;
+use crate::eval::Budget;
+use crate::timeout::Deadlines;
 use crate::models::PassableValue::Function;
-use crate::models::{ExecutionContext, PassableMap, PassableValue};
+use crate::models::{CompiledExecutionContext, EvaluationOptions, EvaluationResult, ExecutionContext, Extension, Explanation, HostCall, HostFailure, PartialExecutionContext, PartialResult, PassableMap, PassableValue, RuleResult, RuleResults, RuleSet, RuleSetContext, RuleSetResult, TimedOutCall};
 use crate::models::PassableValue::PMap;
-use crate::ExecutableType::{CompiledProgram, AST};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::native::{
+    MapEntry, NativeError, NativeExecutionContext, NativeHostContext, NativeLimits, NativeOptions, NativeTimeouts,
+    ValueNode, ValueTree,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::{Bindings, NativeHost};
 use async_trait::async_trait;
 use cel_interpreter::extractors::This;
 use cel_interpreter::objects::{Key, Map, TryIntoValue};
-use cel_interpreter::{Context, ExecutionError, Expression, FunctionContext, Program, Value};
-use std::collections::HashMap;
+use cel_interpreter::{Context, ExecutionError, Expression, FunctionContext, Value};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
@@ -20,9 +45,11 @@ use std::ops::Deref;
 use std::sync::{Arc, mpsc, Mutex};
 use std::thread::spawn;
 use cel_parser::parse;
+use cel_parser::parser::ExpressionParser;
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen_futures::spawn_local;
+use futures::future::join_all;
 #[cfg(not(target_arch = "wasm32"))]
 use futures_lite::future::block_on;
 use uniffi::deps::log::__private_api::log;
@@ -32,14 +59,28 @@ use uniffi::deps::log::__private_api::log;
  * Host context trait that defines the methods that the host context should implement,
  * i.e. iOS or Android calling code. This trait is used to resolve dynamic properties in the
  * CEL expression during evaluation, such as `computed.daysSinceEvent("event_name")` or similar.
- * Note: Since WASM async support in the browser is still not fully mature, we're using the
- * target_arch cfg to define the trait methods differently for WASM and non-WASM targets.
+ * Note: In the browser, host properties are resolved by JS Promises which are not `Send`,
+ * so we're using the target_arch cfg to define the trait differently for WASM and non-WASM targets.
  */
 #[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
 pub trait HostContext: Send + Sync {
-    fn computed_property(&self, name: String, args: String) -> String;
+    async fn computed_property(&self, name: String, args: String) -> String;
 
-    fn device_property(&self, name: String, args: String) -> String;
+    async fn device_property(&self, name: String, args: String) -> String;
+
+    /**
+     * Resolves a property of any host object, i.e. `subscription.daysLeft()`, declared in the `namespaces`
+     * of the definition. By default, `computed` and `device` properties are resolved by the methods above,
+     * which are kept for compatibility, and the properties of other objects fail with a `host_error`.
+     */
+    async fn property(&self, namespace: String, name: String, args: String) -> String {
+        match namespace.as_str() {
+            "computed" => self.computed_property(name, args).await,
+            "device" => self.device_property(name, args).await,
+            _ => unknown_namespace(namespace.as_str()),
+        }
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -48,6 +89,75 @@ pub trait HostContext: Send + Sync {
     async fn computed_property(&self, name: String, args: String) -> String;
 
     async fn device_property(&self, name: String, args: String) -> String;
+
+    /**
+     * Resolves a property of any host object, i.e. `subscription.daysLeft()`, declared in the `namespaces`
+     * of the definition. By default, `computed` and `device` properties are resolved by the methods above,
+     * which are kept for compatibility, and the properties of other objects fail with a `host_error`.
+     */
+    async fn property(&self, namespace: String, name: String, args: String) -> String {
+        match namespace.as_str() {
+            "computed" => self.computed_property(name, args).await,
+            "device" => self.device_property(name, args).await,
+            _ => unknown_namespace(namespace.as_str()),
+        }
+    }
+}
+
+/**
+ * The host interface the evaluation resolves properties with. Values are passed typed, so that hosts which do not
+ * exchange JSON, such as the one of the native API, are called without serializing anything.
+ * The `HostContext` of the JSON API is adapted to it by `JsonHost`.
+ */
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub(crate) trait PropertyHost: Send + Sync {
+    /**
+     * Resolves a property of a host object, i.e. `computed.daysSinceEvent("paywall_open")`.
+     * @return The value of the property, or a `host_error` if the host failed to resolve it
+     */
+    async fn property(&self, object: &str, name: &str, args: &[PassableValue]) -> Result<PassableValue, EvaluationError>;
+}
+
+/**
+ * Adapts a `HostContext` of the JSON API, serializing the arguments and deserializing the response of each call.
+ */
+pub(crate) struct JsonHost(pub(crate) Arc<dyn HostContext>);
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl PropertyHost for JsonHost {
+    async fn property(&self, object: &str, name: &str, args: &[PassableValue]) -> Result<PassableValue, EvaluationError> {
+        let property = format!("{}.{}", object, name);
+        let args = serde_json::to_string(args).map_err(|err| EvaluationError::HostError {
+            message: format!("Could not serialize the arguments of {}: {}", property, err),
+            property: property.clone(),
+        })?;
+        let response = self.0.property(object.to_string(), name.to_string(), args).await;
+        host_response(property, response.as_str())
+    }
+}
+
+/**
+ * The error of a property the host failed to resolve.
+ * @param property The property, named by its object and name, i.e. `computed.daysSinceEvent`
+ * @param reason Why the host failed
+ */
+pub(crate) fn host_failure(property: String, reason: impl fmt::Display) -> EvaluationError {
+    EvaluationError::HostError {
+        message: format!("The host failed to resolve {}: {}", property, reason),
+        property,
+    }
+}
+
+/**
+ * The response of a host context which does not know the object of a property.
+ */
+fn unknown_namespace(namespace: &str) -> String {
+    let failure = HostFailure {
+        error: format!("No host object named {}", namespace),
+    };
+    serde_json::to_string(&failure).unwrap_or_default()
 }
 
 /**
@@ -56,24 +166,32 @@ pub trait HostContext: Send + Sync {
  * @param host The host context to use for resolving properties
  * @return The result of the evaluation, either "true" or "false"
  */
+#[cfg(not(target_arch = "wasm32"))]
 pub fn evaluate_ast_with_context(definition: String, host: Arc<dyn HostContext>) -> String {
-    let data: Result<ASTExecutionContext,_> = serde_json::from_str(definition.as_str());
+    block_on(evaluate_ast_with_context_async(definition, host))
+}
+
+/**
+ * Asynchronous version of `evaluate_ast_with_context`, awaiting the host properties instead of blocking on them.
+ * It is the only version available on WASM, where host properties are resolved by JS Promises.
+ */
+pub async fn evaluate_ast_with_context_async(definition: String, host: Arc<dyn HostContext>) -> String {
+    let data: Result<ASTExecutionContext, _> = serde_json::from_str(definition.as_str());
     let data = match data {
         Ok(data) => data,
-        Err(_) => {
-            let e : Result<_, String> = Err::<ASTExecutionContext,String>("Invalid execution context JSON".to_string());
-            return serde_json::to_string(&e).unwrap()
+        Err(err) => {
+            let e: Result<PassableValue, _> = Err(EvaluationError::deserialization(err));
+            return serde_json::to_string(&e).unwrap();
         }
     };
-    let host = host.clone();
     let res = execute_with(
-        AST(data.expression.into()),
+        &data.expression.into(),
         data.variables,
-        data.computed,
-        data.device,
-        host,
-    ).map(|val| val.to_passable())
-        .map_err(|err| err.to_string());
+        host_properties(data.computed, data.device, data.namespaces),
+        Arc::new(JsonHost(host)),
+        &data.options,
+        data.explain,
+    ).await.map(|val| val.to_passable());
     serde_json::to_string(&res).unwrap()
 }
 
@@ -83,18 +201,18 @@ pub fn evaluate_ast_with_context(definition: String, host: Arc<dyn HostContext>)
  * @return The result of the evaluation, either "true" or "false"
  */
 pub fn evaluate_ast(ast: String) -> String {
-    let data: Result<JSONExpression,_> = serde_json::from_str(ast.as_str());
-    let data : JSONExpression = match data {
+    let data: Result<JSONExpression, _> = serde_json::from_str(ast.as_str());
+    let data: JSONExpression = match data {
         Ok(data) => data,
-        Err(_) => {
-            let e : Result<_, String> = Err::<JSONExpression,String>("Invalid definition for AST Execution".to_string());
-            return serde_json::to_string(&e).unwrap()
+        Err(err) => {
+            let e: Result<PassableValue, _> = Err(EvaluationError::deserialization(err));
+            return serde_json::to_string(&e).unwrap();
         }
     };
     let ctx = Context::default();
     let res = ctx.resolve(&data.into())
         .map(|val| DisplayableValue(val.clone()).to_passable())
-        .map_err(|err| DisplayableError(err).to_string());
+        .map_err(EvaluationError::from);
     serde_json::to_string(&res).unwrap()
 }
 
@@ -104,302 +222,946 @@ pub fn evaluate_ast(ast: String) -> String {
  * @param host The host context to use for resolving properties
  * @return The result of the evaluation, either "true" or "false"
  */
-
+#[cfg(not(target_arch = "wasm32"))]
 pub fn evaluate_with_context(definition: String, host: Arc<dyn HostContext>) -> String {
-    let data: Result<ExecutionContext,_> = serde_json::from_str(definition.as_str());
+    block_on(evaluate_with_context_async(definition, host))
+}
+
+/**
+ * Asynchronous version of `evaluate_with_context`, awaiting the host properties instead of blocking on them.
+ * It is the only version available on WASM, where host properties are resolved by JS Promises.
+ */
+pub async fn evaluate_with_context_async(definition: String, host: Arc<dyn HostContext>) -> String {
+    let data: Result<ExecutionContext, _> = serde_json::from_str(definition.as_str());
     let data: ExecutionContext = match data {
         Ok(data) => data,
-        Err(_) => {
-            let e : Result<ExecutionContext, String> = Err("Invalid execution context JSON".to_string());
-            return serde_json::to_string(&e).unwrap()
+        Err(err) => {
+            let e: Result<PassableValue, _> = Err(EvaluationError::deserialization(err));
+            return serde_json::to_string(&e).unwrap();
         }
     };
-    let compiled = Program::compile(data.expression.as_str())
-        .map(|program| CompiledProgram(program));
+    let compiled = parse_expression(data.expression.as_str());
     let result = match compiled {
         Ok(compiled) => {
             execute_with(
-                compiled,
+                &compiled,
                 data.variables,
-                data.computed,
-                data.device,
-                host,
-            ).map(|val| val.to_passable())
-                .map_err(|err| err.to_string())
+                host_properties(data.computed, data.device, data.namespaces),
+                Arc::new(JsonHost(host)),
+                &data.options,
+                data.explain,
+            ).await.map(|val| val.to_passable())
+        }
+        Err(err) => EvaluationResult::from(err),
+    };
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Evaluate a CEL expression with typed values instead of JSON, avoiding its cost on both sides of the FFI.
+ * @param expression The CEL expression to evaluate
+ * @param context The variables, the platform properties and the options of the evaluation
+ * @param host The host context to use for resolving properties
+ * @return The result of the evaluation, or the error it failed with
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn evaluate_native(
+    expression: String,
+    context: NativeExecutionContext,
+    host: Arc<dyn NativeHostContext>,
+) -> Result<ValueTree, NativeError> {
+    let expression = parse_expression(expression.as_str())?;
+    execute_native(&expression, context, host)
+}
+
+/**
+ * Execute a parsed CEL expression with the typed context and host of the native API.
+ */
+#[cfg(not(target_arch = "wasm32"))]
+fn execute_native(
+    expression: &Expression,
+    context: NativeExecutionContext,
+    host: Arc<dyn NativeHostContext>,
+) -> Result<ValueTree, NativeError> {
+    let bindings = Bindings::try_from(context)?;
+    let result = block_on(execute_with(
+        expression,
+        bindings.variables,
+        host_properties(bindings.computed, bindings.device, bindings.namespaces),
+        Arc::new(NativeHost(host)),
+        &bindings.options,
+        false,
+    )).result?;
+    Ok(ValueTree::from(&result.to_passable()))
+}
+
+/**
+ * Evaluate a set of named CEL expressions against a single context.
+ * The context is created once and results of host property calls are shared between the rules.
+ * @param definition The definition of the rule set, serialized as JSON. The `expressions` are either a map of rule ids
+ * to expressions, which are all evaluated, or an ordered list of `{"id", "expression"}` rules, which is evaluated until
+ * the first rule evaluating to `true`.
+ * @param host The host context to use for resolving properties
+ * @return The results of the evaluated rules keyed by their ids, or for ordered rules the `{"id", "result"}` of the
+ * evaluated rules in their order and the id of the `matched` rule. Duplicate ids of ordered rules are a
+ * `deserialization` error.
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn evaluate_rules(definition: String, host: Arc<dyn HostContext>) -> String {
+    block_on(evaluate_rules_async(definition, host))
+}
+
+/**
+ * Asynchronous version of `evaluate_rules`, awaiting the host properties instead of blocking on them.
+ * It is the only version available on WASM, where host properties are resolved by JS Promises.
+ */
+pub async fn evaluate_rules_async(definition: String, host: Arc<dyn HostContext>) -> String {
+    let data: Result<RuleSetContext, _> = serde_json::from_str(definition.as_str());
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            let e: Result<RuleSetResult, _> = Err(EvaluationError::deserialization(err));
+            return serde_json::to_string(&e).unwrap();
+        }
+    };
+    let (rules, first_match) = match data.expressions {
+        RuleSet::Named(expressions) => {
+            // Evaluated in the order of their ids, so the same rules succeed whatever the order of the map
+            let mut rules: Vec<_> = expressions.into_iter().collect();
+            rules.sort();
+            (rules, false)
+        }
+        RuleSet::Ordered(rules) => {
+            let mut ids = HashSet::new();
+            if let Some(rule) = rules.iter().find(|rule| !ids.insert(rule.id.as_str())) {
+                let err = serde::de::Error::custom(format!("Duplicate rule id: {}", rule.id));
+                let e: Result<RuleSetResult, _> = Err(EvaluationError::deserialization(err));
+                return serde_json::to_string(&e).unwrap();
+            }
+            (rules.into_iter().map(|rule| (rule.id, rule.expression)).collect(), true)
+        }
+    };
+    let rules: Vec<(String, Result<Expression, EvaluationError>)> = rules
+        .into_iter()
+        .map(|(id, expression)| (id, parse_expression(expression.as_str())))
+        .collect();
+    let host: Arc<dyn PropertyHost> = Arc::new(JsonHost(host));
+    let cache = PropertyCache::new(&data.options);
+    let budget = Budget::new(data.options.limits.clone());
+    // Ordered rules after the first one only run if no rule before them matched
+    let prefetched = if first_match { &rules[..rules.len().min(1)] } else { &rules[..] };
+    let parsed: Vec<&Expression> = prefetched.iter().filter_map(|(_, it)| it.as_ref().ok()).collect();
+    let properties = host_properties(data.computed, data.device, data.namespaces);
+    prefetch_properties(&parsed, &properties, &host, &data.options, &cache, &budget).await;
+    let ctx = context_with(
+        data.variables,
+        properties,
+        host.clone(),
+        &data.options,
+        cache.clone(),
+        &budget,
+    );
+    let result = resolve_host_calls(&host, &cache, &budget, || {
+        let mut results = vec![];
+        let mut matched = None;
+        for (id, expression) in rules.iter() {
+            // Each rule has its own steps, while the host calls of all the rules count towards the same limit
+            budget.restart();
+            let value = expression.clone().and_then(|expression| {
+                eval::evaluate(&expression, &ctx, &budget, None)
+                    .map(|val| DisplayableValue(val).to_passable())
+            });
+            let is_match = matches!(value, Ok(PassableValue::Bool(true)));
+            results.push(RuleResult {
+                id: id.clone(),
+                result: value,
+            });
+            if first_match && is_match {
+                matched = Some(id.clone());
+                break;
+            }
+        }
+        let results = if first_match {
+            RuleResults::Ordered(results)
+        } else {
+            RuleResults::Named(results.into_iter().map(|it| (it.id, it.result)).collect())
+        };
+        RuleSetResult { results, matched }
+    }).await;
+    let result = EvaluationResult {
+        result: Ok(result),
+        explain: None,
+        timed_out: cache.timed_out(),
+    };
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Partially evaluate a CEL expression in which some variables or host properties are not known yet.
+ * Everything that does not depend on the unknowns is evaluated, and unknown host properties are never called.
+ * @param definition The definition of the expression, serialized as JSON. Next to the fields of the `ExecutionContext`,
+ * it contains the `unknowns`, i.e. `["user.lastPurchase", "computed.daysSince"]`.
+ * @param host The host context to use for resolving properties
+ * @return Either the `value` of the expression if it does not depend on the unknowns,
+ * or the `residual` AST which only references the unknowns and can be evaluated later
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn partial_evaluate(definition: String, host: Arc<dyn HostContext>) -> String {
+    block_on(partial_evaluate_async(definition, host))
+}
 
+/**
+ * Asynchronous version of `partial_evaluate`, awaiting the host properties instead of blocking on them.
+ * It is the only version available on WASM, where host properties are resolved by JS Promises.
+ */
+pub async fn partial_evaluate_async(definition: String, host: Arc<dyn HostContext>) -> String {
+    let data: Result<PartialExecutionContext, _> = serde_json::from_str(definition.as_str());
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            let e: Result<PartialResult, _> = Err(EvaluationError::deserialization(err));
+            return serde_json::to_string(&e).unwrap();
+        }
+    };
+    let unknowns = data.unknowns;
+    let properties: HostProperties = host_properties(data.computed, data.device, data.namespaces)
+        .into_iter()
+        .map(|(object, properties)| {
+            let properties = properties
+                .into_iter()
+                .filter(|(name, _)| {
+                    !unknowns.iter().any(|it| *it == object || *it == format!("{}.{}", object, name))
+                })
+                .collect();
+            (object, properties)
+        })
+        .collect();
+    let result = match parse_expression(data.expression.as_str()) {
+        Ok(expression) => {
+            let host: Arc<dyn PropertyHost> = Arc::new(JsonHost(host));
+            let cache = PropertyCache::new(&data.options);
+            let budget = Budget::new(data.options.limits.clone());
+            prefetch_properties(&[&expression], &properties, &host, &data.options, &cache, &budget).await;
+            let ctx = context_with(data.variables, properties, host.clone(), &data.options, cache.clone(), &budget);
+            let result = resolve_host_calls(&host, &cache, &budget, || {
+                partial::partial_evaluate(&expression, &ctx, &budget, &unknowns)
+            }).await;
+            EvaluationResult {
+                result,
+                explain: None,
+                timed_out: cache.timed_out(),
+            }
         }
-        Err(e) =>
-            Err("Failed to compile expression".to_string())
+        Err(err) => EvaluationResult::from(err),
     };
     serde_json::to_string(&result).unwrap()
 }
 
+/**
+ * A CEL expression that is compiled once and can be evaluated many times,
+ * with only the variables and the host context changing between evaluations.
+ */
+pub struct CompiledExpression {
+    expression: Result<Expression, EvaluationError>,
+}
+
+impl CompiledExpression {
+    /**
+     * Compiles the given CEL expression.
+     * Compilation errors are not thrown here but returned from every call to `evaluate`.
+     * @param expression The CEL expression to compile
+     */
+    pub fn new(expression: String) -> Self {
+        CompiledExpression {
+            expression: parse_expression(expression.as_str()),
+        }
+    }
+
+    /**
+     * Evaluate the compiled expression with the given context.
+     * @param definition The variables and the platform properties, serialized as JSON.
+     * @param host The host context to use for resolving properties
+     * @return The result of the evaluation, either "true" or "false"
+     */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn evaluate(&self, definition: String, host: Arc<dyn HostContext>) -> String {
+        block_on(self.evaluate_async(definition, host))
+    }
+
+    /**
+     * Asynchronous version of `evaluate`, awaiting the host properties instead of blocking on them.
+     * It is the only version available on WASM, where host properties are resolved by JS Promises.
+     */
+    pub async fn evaluate_async(&self, definition: String, host: Arc<dyn HostContext>) -> String {
+        let data: Result<CompiledExecutionContext, _> = serde_json::from_str(definition.as_str());
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                let e: Result<PassableValue, _> = Err(EvaluationError::deserialization(err));
+                return serde_json::to_string(&e).unwrap();
+            }
+        };
+        let result = match &self.expression {
+            Ok(expression) => execute_with(
+                expression,
+                data.variables,
+                host_properties(data.computed, data.device, data.namespaces),
+                Arc::new(JsonHost(host)),
+                &data.options,
+                false,
+            ).await.map(|val| val.to_passable()),
+            Err(e) => EvaluationResult::from(e.clone()),
+        };
+        serde_json::to_string(&result).unwrap()
+    }
+
+    /**
+     * Evaluate the compiled expression with typed values instead of JSON.
+     * @param context The variables, the platform properties and the options of the evaluation
+     * @param host The host context to use for resolving properties
+     * @return The result of the evaluation, or the error it failed with
+     */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn evaluate_native(
+        &self,
+        context: NativeExecutionContext,
+        host: Arc<dyn NativeHostContext>,
+    ) -> Result<ValueTree, NativeError> {
+        match &self.expression {
+            Ok(expression) => execute_native(expression, context, host),
+            Err(err) => Err(err.clone().into()),
+        }
+    }
+}
+
 /**
  * Transforms a given CEL expression into a CEL AST, serialized as JSON.
  * @param expression The CEL expression to parse
- * @return The AST of the expression, serialized as JSON
+ * @return The AST of the expression wrapped in a JSON `Result`, or a parse error describing where the expression is invalid
  */
 pub fn parse_to_ast(expression: String) -> String {
-    let ast: Result<JSONExpression, _> = parse(expression.as_str()).map(|expr| expr.into());
-    let ast = ast
-        .map_err(|err| err.to_string());
-    serde_json::to_string(&ast.unwrap()).unwrap()
+    let ast: Result<JSONExpression, EvaluationError> =
+        parse_expression(expression.as_str()).map(|expr| expr.into());
+    serde_json::to_string(&ast).unwrap()
+}
+
+/**
+ * Transforms a CEL AST, serialized as JSON, back into CEL source, using only the parentheses the expression requires.
+ * @param ast The AST of the expression, as returned by `parse_to_ast`
+ * @return The CEL source wrapped in a JSON `Result`, or an `unsupported` error if the AST contains a node
+ * that cannot be written in CEL source, such as an `uint` literal
+ */
+pub fn ast_to_expression(ast: String) -> String {
+    let result = serde_json::from_str::<JSONExpression>(ast.as_str())
+        .map_err(EvaluationError::deserialization)
+        .and_then(|ast| printer::print(&ast.into()));
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Optimizes a CEL AST, serialized as JSON, by folding constant sub-expressions, simplifying boolean identities
+ * and removing double negations. The optimized AST evaluates to the same value, or fails with the same error.
+ * @param ast The AST of the expression, as returned by `parse_to_ast`
+ * @return The optimized AST wrapped in a JSON `Result`, or a deserialization error
+ */
+pub fn optimize_ast(ast: String) -> String {
+    let result = serde_json::from_str::<JSONExpression>(ast.as_str())
+        .map_err(EvaluationError::deserialization)
+        .map(|ast| JSONExpression::from(optimizer::optimize(&ast.into())));
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Statically analyzes a CEL expression without evaluating it, listing the variables, host properties
+ * and functions it references, so the host can prepare only the data the expression needs.
+ * @param expression The CEL expression to analyze
+ * @param namespaces The host objects declared in the `namespaces` of the definition, i.e. `["subscription"]`,
+ * whose properties are listed like the `computed` and `device` ones
+ * @return The analysis of the expression wrapped in a JSON `Result`, or a parse error
+ */
+pub fn analyze_expression(expression: String, namespaces: Vec<String>) -> String {
+    let result = parse_expression(expression.as_str()).map(|expr| analysis::analyze(&expr, &namespaces));
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Statically analyzes a CEL AST without evaluating it, same as `analyze_expression`.
+ * @param ast The AST of the expression, serialized as JSON
+ * @param namespaces The host objects declared in the `namespaces` of the definition
+ * @return The analysis of the expression wrapped in a JSON `Result`, or a deserialization error
+ */
+pub fn analyze_ast(ast: String, namespaces: Vec<String>) -> String {
+    let result = serde_json::from_str::<JSONExpression>(ast.as_str())
+        .map_err(EvaluationError::deserialization)
+        .map(|ast| analysis::analyze(&ast.into(), &namespaces));
+    serde_json::to_string(&result).unwrap()
+}
+
+/**
+ * Type checks a CEL expression against the declared types of the variables and signatures of the host properties,
+ * without evaluating it, so invalid rules can be rejected before they are published.
+ * @param expression The CEL expression to check
+ * @param declarations The `variables` types and the `computed`, `device` and `namespaces` signatures, serialized as JSON
+ * @return The inferred `type` of the expression and the `errors` found, wrapped in a JSON `Result`,
+ * or a parse or deserialization error
+ */
+pub fn check_expression(expression: String, declarations: String) -> String {
+    let result = serde_json::from_str::<checker::Declarations>(declarations.as_str())
+        .map_err(EvaluationError::deserialization)
+        .and_then(|declarations| {
+            parse_expression(expression.as_str()).map(|expr| checker::check(&expr, &declarations))
+        });
+    serde_json::to_string(&result).unwrap()
 }
 
 /**
-Type of expression to be executed, either a compiled program or an AST.
+ * Parses a CEL expression, returning a structured parse error with the location of the problem.
+ * @param expression The CEL expression to parse
  */
-enum ExecutableType {
-    AST(Expression),
-    CompiledProgram(Program),
+fn parse_expression(expression: &str) -> Result<Expression, EvaluationError> {
+    literals::check(expression)?;
+    ExpressionParser::new()
+        .parse(expression)
+        .map_err(|err| EvaluationError::parse(expression, err))
 }
 
 /**
- * Execute a CEL expression, either compiled or pure AST; with the given context.
- * @param executable The executable type, either an AST or a compiled program
+ * Execute a parsed CEL expression, coming either from source or from a JSON AST, with the given context.
+ * @param expression The parsed expression to execute
  * @param variables The variables to use in the expression
- * @param platform The platform properties or functions to use in the expression
+ * @param properties The host properties or functions to use in the expression, by object
  * @param host The host context to use for resolving properties
+ * @param options The options describing how the expression is evaluated
+ * @param explain Whether to return the trace of the evaluation and the host calls made next to its result
+ * @return The result of the evaluation, with the host calls which timed out
  */
-fn execute_with(
-    executable: ExecutableType,
+async fn execute_with(
+    expression: &Expression,
     variables: PassableMap,
+    properties: HostProperties,
+    host: Arc<dyn PropertyHost + 'static>,
+    options: &EvaluationOptions,
+    explain: bool,
+) -> EvaluationResult<DisplayableValue> {
+    let cache = if explain {
+        PropertyCache::new(options).recording()
+    } else {
+        PropertyCache::new(options)
+    };
+    let budget = Budget::new(options.limits.clone());
+    prefetch_properties(&[expression], &properties, &host, options, &cache, &budget).await;
+    let ctx = context_with(variables, properties, host.clone(), options, cache.clone(), &budget);
+    let (result, explain) = if explain {
+        let mut trace = vec![];
+        let result = resolve_host_calls(&host, &cache, &budget, || {
+            trace.clear();
+            eval::evaluate(expression, &ctx, &budget, Some(&mut trace))
+        }).await;
+        let explanation = Explanation {
+            trace: trace.pop(),
+            host_calls: cache.recorded(),
+        };
+        (result, Some(explanation))
+    } else {
+        let result = resolve_host_calls(&host, &cache, &budget, || eval::evaluate(expression, &ctx, &budget, None)).await;
+        (result, None)
+    };
+    EvaluationResult {
+        result: result.map(DisplayableValue),
+        explain,
+        timed_out: cache.timed_out(),
+    }
+}
+
+/**
+ * The host properties declared for an evaluation, by the object they are accessed through in the expression,
+ * i.e. `computed`, `device` or any of the `namespaces`, and then by name, with the arguments they are declared with.
+ */
+type HostProperties = HashMap<String, HashMap<String, Vec<PassableValue>>>;
+
+/**
+ * Collects the host properties declared in a definition. The `computed` and `device` objects are always declared,
+ * and properties they also declare in the `namespaces` are merged with them.
+ */
+fn host_properties(
     computed: Option<HashMap<String, Vec<PassableValue>>>,
     device: Option<HashMap<String, Vec<PassableValue>>>,
-    host: Arc<dyn HostContext + 'static>,
-) -> Result<DisplayableValue, DisplayableError> {
-    let host = host.clone();
-    let host = Arc::new(Mutex::new(host));
-    let mut ctx = Context::default();
-    // Isolate device to re-bind later
-    let device_map = variables.clone();
-    let device_map = device_map.map.get("device").clone().unwrap_or(&PMap(HashMap::new())).clone();
+    namespaces: Option<HostProperties>,
+) -> HostProperties {
+    let mut properties = namespaces.unwrap_or_default();
+    properties.entry("computed".to_string()).or_default().extend(computed.unwrap_or_default());
+    properties.entry("device".to_string()).or_default().extend(device.unwrap_or_default());
+    properties
+}
 
-    // Add predefined variables locally to the context
-    variables
-        .map
-        .iter()
-        .for_each(|it| {
-            let _ = ctx.add_variable(it.0.as_str(), it.1.to_cel());
-        });
-    // Add maybe function
-    ctx.add_function("maybe", maybe);
+/**
+ * Results of host property calls, keyed by the object, name and serialized arguments of the property.
+ * Each evaluation has its own cache so that each distinct host call is made only once,
+ * while rule sets share one cache between all of their rules.
+ */
+#[derive(Clone, Default)]
+struct PropertyCache {
+    values: Arc<Mutex<HashMap<PropertyKey, Result<PassableValue, EvaluationError>>>>,
+    // Every call made to the host, only recorded when explaining the evaluation
+    calls: Option<Arc<Mutex<Vec<HostCall>>>>,
+    // Properties the evaluation needed but could not wait for, see `resolve_host_calls`
+    pending: Arc<Mutex<Vec<PropertyCall>>>,
+    deadlines: Deadlines,
+    timed_out: Arc<Mutex<Vec<TimedOutCall>>>,
+}
 
-    // This function is used to extract the value of a property from the host context
-    // As UniFFi doesn't support recursive enums yet, we have to pass it in as a
-    // JSON serialized string of a PassableValue from Host and deserialize it here
+type PropertyKey = (Arc<String>, String, String);
 
-    enum PropType {
-        Computed,
-        Device,
-    }
-    #[cfg(not(target_arch = "wasm32"))]
-    fn prop_for(
-        prop_type: PropType,
-        name: Arc<String>,
-        args: Option<Vec<PassableValue>>,
-        ctx: &Arc<dyn HostContext>,
-    ) -> Result<PassableValue, String> {
-        // Get computed property
-        let val = futures_lite::future::block_on(async move {
-            let ctx = ctx.clone();
-            let args = if let Some(args) = args {
-                serde_json::to_string(&args)
-            } else {
-                serde_json::to_string::<Vec<PassableValue>>(&vec![])
-            };
-            match args {
-                Ok(args) => {
-                    match prop_type {
-                        PropType::Computed => Ok(ctx.computed_property(
-                            name.clone().to_string(),
-                            args,
-                        ).await),
-                        PropType::Device => Ok(ctx.device_property(
-                            name.clone().to_string(),
-                            args,
-                        ).await),
-                    }
-                }
-                Err(e) => {
-                    Err(ExecutionError::UndeclaredReference(name).to_string())
-                }
-            }
-        });
-        // Deserialize the value
-        let passable: Result<PassableValue, String> =
-            val.map(|val| serde_json::from_str(val.as_str()).unwrap_or(PassableValue::Null))
-                .map_err(|err| err.to_string());
+/**
+ * A call to make to the host, with its key in the cache, the object and name of the property and its arguments.
+ */
+type PropertyCall = (PropertyKey, Arc<String>, Arc<String>, Vec<PassableValue>);
+
+fn property_key(object: &Arc<String>, name: &str, args: &[PassableValue]) -> PropertyKey {
+    (
+        object.clone(),
+        name.to_string(),
+        serde_json::to_string(args).unwrap_or_default(),
+    )
+}
 
-        passable
+impl PropertyCache {
+    fn get(&self, key: &PropertyKey) -> Option<Result<PassableValue, EvaluationError>> {
+        self.values.lock().ok().and_then(|values| values.get(key).cloned())
     }
 
-    #[cfg(target_arch = "wasm32")]
-    fn prop_for(
-        prop_type: PropType,
-        name: Arc<String>,
-        args: Option<Vec<PassableValue>>,
-        ctx: &Arc<dyn HostContext>,
-    ) -> Option<PassableValue> {
-        let ctx = ctx.clone();
-
-        let val = match prop_type {
-            PropType::Computed => ctx.computed_property(
-                name.clone().to_string(),
-                serde_json::to_string(&args).expect("Failed to serialize args for computed property"),
-            ),
-            PropType::Device => ctx.device_property(
-                name.clone().to_string(),
-                serde_json::to_string(&args).expect("Failed to serialize args for computed property"),
-            ),
-        };
-        // Deserialize the value
-        let passable: Option<PassableValue> = serde_json::from_str(val.as_str()).unwrap_or(Some(PassableValue::Null));
+    fn insert(&self, key: PropertyKey, value: Result<PassableValue, EvaluationError>) {
+        if let Ok(mut values) = self.values.lock() {
+            values.insert(key, value);
+        }
+    }
 
-        passable
+    /**
+     * Creates the cache of an evaluation, starting the deadline of its host calls.
+     * @param options The options declaring the timeouts and fallbacks of the host calls
+     */
+    fn new(options: &EvaluationOptions) -> Self {
+        PropertyCache {
+            deadlines: Deadlines::start(options.timeouts.as_ref(), options.fallbacks.as_ref()),
+            ..Default::default()
+        }
     }
 
-    let computed = computed.unwrap_or(HashMap::new()).clone();
+    /**
+     * Makes the cache also record every call made to the host.
+     */
+    fn recording(self) -> Self {
+        PropertyCache {
+            calls: Some(Arc::default()),
+            ..self
+        }
+    }
 
-    // Create computed properties as a map of keys and function names
-    let computed_host_properties: HashMap<Key, Value> = computed
-        .iter()
-        .map(|it| {
-            let args = it.1.clone();
-            let args = if args.is_empty() {
-                None
-            } else {
-                Some(Box::new(PassableValue::List(args)))
+    fn record(&self, object: &str, name: &str, args: &[PassableValue], result: &Result<PassableValue, EvaluationError>) {
+        if let Some(Ok(mut calls)) = self.calls.as_ref().map(|it| it.lock()) {
+            let result = match result {
+                Ok(value) => serde_json::to_string(value),
+                Err(err) => serde_json::to_string(&HostFailure { error: err.to_string() }),
             };
-            let name = it.0.clone();
-            (
-                Key::String(Arc::new(name.clone())),
-                Function(name, args).to_cel(),
-            )
-        })
-        .collect();
+            calls.push(HostCall {
+                object: object.to_string(),
+                name: name.to_string(),
+                args: args.to_vec(),
+                result: result.unwrap_or_default(),
+            });
+        }
+    }
 
-    let device = device.unwrap_or(HashMap::new()).clone();
+    /**
+     * Checks if the evaluation already requested the property, which is then being resolved.
+     */
+    #[cfg(target_arch = "wasm32")]
+    fn is_pending(&self, key: &PropertyKey) -> bool {
+        self.pending
+            .lock()
+            .map(|pending| pending.iter().any(|it| it.0 == *key))
+            .unwrap_or(false)
+    }
 
+    /**
+     * Requests a property to be resolved before the evaluation is run again.
+     */
+    #[cfg(target_arch = "wasm32")]
+    fn request(&self, call: PropertyCall) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.push(call);
+        }
+    }
 
-    // From defined properties the device properties
-    let total_device_properties = if let PMap(map) = device_map {
-        map
-    } else {
-        HashMap::new()
-    };
+    fn take_pending(&self) -> Vec<PropertyCall> {
+        self.pending
+            .lock()
+            .map(|mut pending| std::mem::take(&mut *pending))
+            .unwrap_or_default()
+    }
 
-    // Create device properties as a map of keys and function names
-    let device_host_properties: HashMap<Key, Value> = device
-        .iter()
-        .map(|it| {
-            let args = it.1.clone();
-            let args = if args.is_empty() {
-                None
-            } else {
-                Some(Box::new(PassableValue::List(args)))
-            };
-            let name = it.0.clone();
-            (
-                Key::String(Arc::new(name.clone())),
-                Function(name, args).to_cel(),
-            )
+    /**
+     * Records a host call which timed out, and returns its fallback value or the error it fails with.
+     */
+    fn time_out(&self, object: &str, name: &str, args: &[PassableValue]) -> Result<PassableValue, EvaluationError> {
+        let property = format!("{}.{}", object, name);
+        let fallback = self.deadlines.fallback(property.as_str());
+        if let Ok(mut timed_out) = self.timed_out.lock() {
+            timed_out.push(TimedOutCall {
+                object: object.to_string(),
+                name: name.to_string(),
+                args: args.to_vec(),
+                fallback: fallback.is_some(),
+            });
+        }
+        fallback.ok_or_else(|| EvaluationError::HostError {
+            message: format!("The host did not resolve {} before the deadline", property),
+            property,
         })
-        .chain(total_device_properties.iter().map(|(k, v)| (Key::String(Arc::new(k.clone())), v.to_cel().clone())))
-        .collect();
-
-    // Add the map to the `computed` object
-    let _ = ctx.add_variable(
-        "computed",
-        Value::Map(Map {
-            map: Arc::new(computed_host_properties),
-        }),
-    );
-
-    // Add the map to the `device` object
-    let _ = ctx.add_variable(
-        "device",
-        Value::Map(Map {
-            map: Arc::new(device_host_properties),
-        }),
-    );
+    }
 
+    fn timed_out(&self) -> Vec<TimedOutCall> {
+        self.timed_out
+            .lock()
+            .map(|it| it.clone())
+            .unwrap_or_default()
+    }
 
-    let binding = device.clone();
-    // Combine the device and computed properties
-    let host_properties = binding
-        .iter()
-        .chain(computed.iter())
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .into_iter();
-
-    let mut device_properties_clone = device.clone().clone();
-    // Add those functions to the context
-    for it in host_properties {
-        let mut value = device_properties_clone.clone();
-        let key = it.0.clone();
-        let host_clone = Arc::clone(&host); // Clone the Arc to pass into the closure
-        let key_str = key.clone(); // Clone key for usage in the closure
-        ctx.add_function(
-            key_str.as_str(),
-            move |ftx: &FunctionContext| -> Result<Value, ExecutionError> {
-                let device = value.clone();
-                let fx = ftx.clone();
-                let name = fx.name.clone(); // Move the name into the closure
-                let args = fx.args.clone(); // Clone the arguments
-                let host = host_clone.lock(); // Lock the host for safe access
-                match host {
-                    Ok(host) => {
-                        prop_for(
-                            if device.contains_key(&it.0)
-                            { PropType::Device } else { PropType::Computed },
-                            name.clone(),
-                            Some(
-                                args.iter()
-                                    .map(|expression| {
-                                        DisplayableValue(ftx.ptx.resolve(expression).unwrap()).to_passable()
-                                    })
-                                    .collect(),
-                            ),
-                            &*host,
-                        )
-                            .map_or(Err(ExecutionError::UndeclaredReference(name)), |v| {
-                                Ok(v.to_cel())
-                            })
-                    }
-                    Err(e) => {
-                        let e = e.to_string();
-                        let name = name.clone().to_string();
-                        let error = ExecutionError::FunctionError { function: name, message: e };
-                        Err(error)
-                    }
-                }
-            },
-        );
+    fn recorded(&self) -> Vec<HostCall> {
+        self.calls
+            .as_ref()
+            .and_then(|it| it.lock().ok().map(|calls| calls.clone()))
+            .unwrap_or_default()
     }
+}
 
-    let val = match executable {
-        AST(ast) => &ctx.resolve(&ast),
-        CompiledProgram(program) => &program.execute(&ctx),
+// These functions are used to extract the value of a property from the host context
+async fn fetch_property(
+    object: Arc<String>,
+    name: Arc<String>,
+    args: Option<Vec<PassableValue>>,
+    ctx: &Arc<dyn PropertyHost>,
+    cache: &PropertyCache,
+) -> Result<PassableValue, EvaluationError> {
+    let args = args.unwrap_or_default();
+    let call = ctx.property(object.as_str(), name.as_str(), &args);
+    let val = match cache.deadlines.run(call).await {
+        Some(val) => val,
+        None => return cache.time_out(object.as_str(), name.as_str(), &args),
     };
+    cache.record(object.as_str(), name.as_str(), &args, &val);
+    val
+}
 
-    val.clone().map(|val| DisplayableValue(val.clone()))
-        .map_err(|err| DisplayableError(err))
+/**
+ * Deserializes the JSON returned by the host for a property, which is either a `PassableValue`
+ * or a `{"error": "..."}` object if the host failed to resolve it.
+ * @param property The property, named by its object and name, i.e. `computed.daysSinceEvent`
+ * @param response The JSON returned by the host
+ * @return The value of the property, or a `host_error` if the host failed or returned invalid JSON
+ */
+fn host_response(property: String, response: &str) -> Result<PassableValue, EvaluationError> {
+    let invalid = match serde_json::from_str::<PassableValue>(response) {
+        Ok(value) => return Ok(value),
+        Err(err) => err,
+    };
+    match serde_json::from_str::<HostFailure>(response) {
+        Ok(failure) => Err(host_failure(property, failure.error)),
+        Err(_) => Err(EvaluationError::HostError {
+            message: format!("The host returned an invalid value for {}: {}", property, invalid),
+            property,
+        }),
+    }
 }
 
-pub fn maybe(
-    ftx: &FunctionContext,
-    This(_this): This<Value>,
-    left: Expression,
-    right: Expression,
-) -> Result<Value, ExecutionError> {
-    return ftx.ptx.resolve(&left).or_else(|_| ftx.ptx.resolve(&right));
+#[cfg(not(target_arch = "wasm32"))]
+fn prop_for(
+    object: Arc<String>,
+    name: Arc<String>,
+    args: Option<Vec<PassableValue>>,
+    ctx: &Arc<dyn PropertyHost>,
+    cache: &PropertyCache,
+) -> Result<PassableValue, EvaluationError> {
+    block_on(fetch_property(object, name, args, ctx, cache))
 }
 
-// Wrappers around CEL values used so that we can create extensions on them
-pub struct DisplayableValue(Value);
+/**
+ * Resolves a property missing from the cache during the evaluation by blocking on the host.
+ * @param call The call to make to the host
+ * @param memoized Whether the result is stored in the cache
+ * @param host The host context to use for resolving properties
+ * @param cache The cache for host property results
+ * @param budget The budget limiting the number of host calls
+ */
+#[cfg(not(target_arch = "wasm32"))]
+fn resolve_property(
+    call: PropertyCall,
+    memoized: bool,
+    host: &Arc<dyn PropertyHost>,
+    cache: &PropertyCache,
+    budget: &Budget,
+) -> Result<Value, ExecutionError> {
+    let (key, object, name, args) = call;
+    budget.host_call()
+        .map_err(|err| ExecutionError::function_error(name.as_str(), err))?;
+    let value = prop_for(object, name.clone(), Some(args), host, cache);
+    if memoized {
+        cache.insert(key, value.clone());
+    }
+    value
+        .map(|it| it.to_cel())
+        .map_err(|err| budget.host_error(name.as_str(), err))
+}
 
-pub struct DisplayableError(ExecutionError);
+/**
+ * Requests a property missing from the cache during the evaluation, since it cannot block on a JS Promise.
+ * The evaluation then fails and its result is discarded, see `resolve_host_calls`.
+ */
+#[cfg(target_arch = "wasm32")]
+fn resolve_property(
+    call: PropertyCall,
+    _memoized: bool,
+    _host: &Arc<dyn PropertyHost>,
+    cache: &PropertyCache,
+    budget: &Budget,
+) -> Result<Value, ExecutionError> {
+    let name = call.2.clone();
+    if !cache.is_pending(&call.0) {
+        budget.host_call()
+            .map_err(|err| ExecutionError::function_error(name.as_str(), err))?;
+        cache.request(call);
+    }
+    Err(ExecutionError::function_error(name.as_str(), "The host property is not resolved yet"))
+}
+
+/**
+ * Runs an evaluation until every host property it needs is resolved.
+ * On native targets, the properties missing from the cache are resolved during the evaluation by blocking on the host,
+ * so the evaluation runs once. On WASM, the evaluation cannot block on a JS Promise, so it requests the missing
+ * properties instead and fails. They are then awaited concurrently and the evaluation runs again, until it misses none.
+ * @param host The host context to use for resolving properties
+ * @param cache The cache the resolved properties are stored into
+ * @param budget The budget of the evaluation, whose steps are counted again when it runs again
+ * @param evaluate Runs the evaluation
+ */
+async fn resolve_host_calls<R>(
+    host: &Arc<dyn PropertyHost>,
+    cache: &PropertyCache,
+    budget: &Budget,
+    mut evaluate: impl FnMut() -> R,
+) -> R {
+    loop {
+        let result = evaluate();
+        let pending = cache.take_pending();
+        if pending.is_empty() {
+            return result;
+        }
+        let fetches = pending.iter().map(|(_, object, name, args)| {
+            fetch_property(object.clone(), name.clone(), Some(args.clone()), host, cache)
+        });
+        for ((key, ..), value) in pending.iter().zip(join_all(fetches).await) {
+            cache.insert(key.clone(), value);
+        }
+        budget.restart();
+    }
+}
+
+/**
+ * Resolves the host properties called with constant arguments in the given expressions before the evaluation starts
+ * and stores them in the cache, so that the evaluation does not have to wait for each of them in turn.
+ * The host is called for all of them concurrently. Calls in branches the evaluation might skip, such as the right side
+ * of `||`, are left to the evaluation, so they do not use up the host call limit or time out when never needed.
+ * @param expressions The expressions that will be evaluated
+ * @param properties The host properties or functions declared for the expressions, by object
+ * @param host The host context to use for resolving properties
+ * @param options The options describing how the expression is evaluated
+ * @param cache The cache the resolved properties are stored into
+ * @param budget The budget limiting the number of host calls
+ */
+async fn prefetch_properties(
+    expressions: &[&Expression],
+    properties: &HostProperties,
+    host: &Arc<dyn PropertyHost>,
+    options: &EvaluationOptions,
+    cache: &PropertyCache,
+    budget: &Budget,
+) {
+    let volatile = options.volatile.clone().unwrap_or_default();
+    let mut calls: Vec<PropertyCall> = vec![];
+    for expression in expressions {
+        for (object, name, args) in analysis::constant_host_calls(expression) {
+            let declared = properties.get(object.as_str()).map(|it| it.contains_key(name.as_str()));
+            if declared != Some(true) || volatile.contains(&format!("{}.{}", object, name)) {
+                continue;
+            }
+            let args: Vec<PassableValue> = args.into_iter()
+                .map(|it| DisplayableValue(it).to_passable())
+                .collect();
+            let key = property_key(&object, name.as_str(), &args);
+            if cache.get(&key).is_some() || calls.iter().any(|it| it.0 == key) {
+                continue;
+            }
+            calls.push((key, object, name, args));
+        }
+    }
+    calls.truncate(budget.reserve_host_calls(calls.len()));
+
+    let fetches = calls.iter().map(|(_, object, name, args)| {
+        fetch_property(object.clone(), name.clone(), Some(args.clone()), host, cache)
+    });
+    let values = join_all(fetches).await;
+    for ((key, ..), value) in calls.into_iter().zip(values) {
+        cache.insert(key, value);
+    }
+}
+
+/**
+ * Create the CEL context used to execute expressions, containing the variables,
+ * the host objects such as `computed` and `device` and the functions resolving host properties.
+ * @param variables The variables to use in the expression
+ * @param properties The host properties or functions to use in the expression, by object
+ * @param host The host context to use for resolving properties
+ * @param options The options describing how the expression is evaluated
+ * @param cache The cache for host property results, shared by all the evaluations using this context
+ * @param budget The budget limiting the number of host calls
+ */
+fn context_with(
+    variables: PassableMap,
+    properties: HostProperties,
+    host: Arc<dyn PropertyHost + 'static>,
+    options: &EvaluationOptions,
+    cache: PropertyCache,
+    budget: &Budget,
+) -> Context<'static> {
+    let mut ctx = Context::default();
+
+    // Add predefined variables locally to the context
+    variables
+        .map
+        .iter()
+        .for_each(|it| {
+            let _ = ctx.add_variable(it.0.as_str(), it.1.to_cel());
+        });
+    add_library_functions(&mut ctx);
+    let now = options.now.or_else(datetime::current_time);
+    ctx.add_function("now", move |ftx: &FunctionContext| datetime::now(ftx, now));
+    for extension in options.extensions.iter().flatten() {
+        match extension {
+            Extension::Strings => strings::add_functions(&mut ctx),
+        }
+    }
+
+    // Lazy attributes are resolved like properties of their object, which they declare if no property does
+    let mut properties = properties;
+    let mut lazy: HashMap<String, Vec<String>> = HashMap::new();
+    for attribute in options.lazy.iter().flatten() {
+        if let Some((object, name)) = attribute.split_once('.') {
+            properties.entry(object.to_string()).or_default();
+            lazy.entry(object.to_string()).or_default().push(name.to_string());
+        }
+    }
+
+    for (object, declared) in properties {
+        // Create the host properties as a map of keys and function names,
+        // next to the values of the variable of the same name, i.e. the `device` variable
+        let values = match variables.map.get(&object) {
+            Some(PMap(values)) => values.clone(),
+            _ => HashMap::new(),
+        };
+        let host_properties: HashMap<Key, Value> = declared
+            .iter()
+            .map(|it| {
+                let args = it.1.clone();
+                let args = if args.is_empty() {
+                    None
+                } else {
+                    Some(Box::new(PassableValue::List(args)))
+                };
+                let name = it.0.clone();
+                (
+                    Key::String(Arc::new(name.clone())),
+                    Function(name, args).to_cel(),
+                )
+            })
+            .chain(values.iter().map(|(k, v)| (Key::String(Arc::new(k.clone())), v.to_cel())))
+            .collect();
+
+        // Add the map to the object
+        let _ = ctx.add_variable(
+            object.as_str(),
+            Value::Map(Map {
+                map: Arc::new(host_properties),
+            }),
+        );
+
+        // Register the host properties under their object, i.e. `computed.daysSinceEvent`, which is not a valid
+        // identifier, so the same name can be declared on several objects and no property can shadow another function.
+        // Lazy attributes are left out of the map, so that accessing them calls their function, see `eval::lazy_attribute`
+        let attributes = lazy.remove(&object).unwrap_or_default();
+        let names: HashSet<String> = declared.into_keys().chain(attributes).collect();
+        let object = Arc::new(object);
+        for name in names {
+            let name = Arc::new(name);
+            let object = object.clone();
+            let host_clone = Arc::clone(&host); // Clone the Arc to pass into the closure
+            let cache = cache.clone();
+            let budget = budget.clone();
+            let property = format!("{}.{}", object, name);
+            // On WASM, volatile properties are resolved once per evaluation, since it runs again once they are
+            let memoized = cfg!(target_arch = "wasm32") || !options.volatile.iter().flatten().any(|it| *it == property);
+            ctx.add_function(
+                property.as_str(),
+                move |ftx: &FunctionContext| -> Result<Value, ExecutionError> {
+                    let args: Vec<PassableValue> = ftx.args.iter()
+                        .map(|expression| {
+                            ftx.ptx.resolve(expression).map(|it| DisplayableValue(it).to_passable())
+                        })
+                        .collect::<Result<_, _>>()?;
+                    let key = property_key(&object, name.as_str(), &args);
+                    if let Some(value) = cache.get(&key).filter(|_| memoized) {
+                        return value
+                            .map(|it| it.to_cel())
+                            .map_err(|err| budget.host_error(name.as_str(), err));
+                    }
+                    let call = (key, object.clone(), name.clone(), args);
+                    resolve_property(call, memoized, &host_clone, &cache, &budget)
+                },
+            );
+        }
+    }
+
+    ctx
+}
+
+/**
+ * Adds the functions this library provides on top of the ones built into the interpreter.
+ * @param ctx The context to add the functions to
+ */
+fn add_library_functions(ctx: &mut Context) {
+    ctx.add_function("maybe", maybe);
+    ctx.add_function("semver", semver::semver);
+    ctx.add_function("versionCompare", semver::version_compare);
+    ctx.add_function("versionAtLeast", semver::version_at_least);
+    ctx.add_function("fnv1a64", hashing::fnv1a64);
+    ctx.add_function("murmur3", hashing::murmur3);
+    ctx.add_function("bucket", hashing::bucket);
+    ctx.add_function("percentRollout", hashing::percent_rollout);
+    datetime::add_functions(ctx);
+}
+
+pub fn maybe(
+    ftx: &FunctionContext,
+    This(_this): This<Value>,
+    left: Expression,
+    right: Expression,
+) -> Result<Value, ExecutionError> {
+    return ftx.ptx.resolve(&left).or_else(|_| ftx.ptx.resolve(&right));
+}
+
+// Wrappers around CEL values used so that we can create extensions on them
+pub struct DisplayableValue(Value);
+
+pub struct DisplayableError(ExecutionError);
 
 impl fmt::Display for DisplayableValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -470,6 +1232,67 @@ mod tests {
         }
     }
 
+    struct CountingContext {
+        value: String,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingContext {
+        fn new(value: PassableValue) -> Self {
+            CountingContext {
+                value: serde_json::to_string(&value).unwrap(),
+                calls: std::sync::atomic::AtomicUsize::new(0),
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl HostContext for CountingContext {
+        async fn computed_property(&self, _name: String, _args: String) -> String {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.value.clone()
+        }
+
+        async fn device_property(&self, _name: String, _args: String) -> String {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.value.clone()
+        }
+    }
+
+    struct ConcurrentContext {
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ConcurrentContext {
+        async fn resolve(&self) -> String {
+            use std::sync::atomic::Ordering::SeqCst;
+            self.in_flight.fetch_add(1, SeqCst);
+            // Give other calls a chance to start before this one finishes
+            for _ in 0..100 {
+                self.max_in_flight.fetch_max(self.in_flight.load(SeqCst), SeqCst);
+                futures_lite::future::yield_now().await;
+            }
+            self.in_flight.fetch_sub(1, SeqCst);
+            serde_json::to_string(&PassableValue::Int(1)).unwrap()
+        }
+    }
+
+    #[async_trait]
+    impl HostContext for ConcurrentContext {
+        async fn computed_property(&self, _name: String, _args: String) -> String {
+            self.resolve().await
+        }
+
+        async fn device_property(&self, _name: String, _args: String) -> String {
+            self.resolve().await
+        }
+    }
+
     #[tokio::test]
     async fn test_variables() {
         let ctx = Arc::new(TestContext {
@@ -535,7 +1358,7 @@ mod tests {
                 .to_string(),
             ctx,
         );
-        assert_eq!(res, "{\"Err\":\"Undeclared reference to 'test_custom_func'\"}");
+        assert_eq!(res, "{\"Err\":{\"kind\":\"undeclared_reference\",\"message\":\"Undeclared reference to 'test_custom_func'\",\"identifier\":\"test_custom_func\"}}");
     }
 
     #[test]
@@ -633,7 +1456,7 @@ mod tests {
             ctx,
         );
         println!("{}", res.clone());
-        assert_eq!(res, "{\"Err\":\"No such key: should_display\"}");
+        assert_eq!(res, "{\"Err\":{\"kind\":\"undeclared_reference\",\"message\":\"No such key: should_display\",\"identifier\":\"should_display\"}}");
     }
 
 
@@ -800,6 +1623,287 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_invalid_expression_returns_parse_error_with_span() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "foo == )"
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        let res: Result<PassableValue, EvaluationError> = serde_json::from_str(&res).unwrap();
+        match res {
+            Err(EvaluationError::Parse { span, .. }) => {
+                assert_eq!(span, Some(error::Span { start: 7, end: 8 }))
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_context_returns_deserialization_error() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let res = evaluate_with_context("{\"expression\": \"true\"}".to_string(), ctx);
+        assert!(res.starts_with("{\"Err\":{\"kind\":\"deserialization\""));
+    }
+
+    #[test]
+    fn test_mismatched_types_return_type_mismatch_error() {
+        let ast: JSONExpression = parse("1 + 'a'").unwrap().into();
+        let res = evaluate_ast(serde_json::to_string(&ast).unwrap());
+        let res: Result<PassableValue, EvaluationError> = serde_json::from_str(&res).unwrap();
+        assert!(matches!(res, Err(EvaluationError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_timestamp_and_duration_arithmetic() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": {
+             "map" : {
+                    "now": {"type": "timestamp", "value": "2024-05-10T12:00:00Z"},
+                    "user": {"type": "map", "value": {
+                        "lastPurchase": {"type": "timestamp", "value": 1715040000}
+                    }}
+            }},
+            "expression": "now - user.lastPurchase > duration('72h')"
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+    }
+
+    #[test]
+    fn test_timestamp_and_duration_round_trip() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": {
+             "map" : {
+                    "start": {"type": "timestamp", "value": "2024-05-10T12:00:00+02:00"},
+                    "length": {"type": "duration", "value": "1h30m"}
+            }},
+            "expression": "[start + length, length + duration('0.5s')]"
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        assert_eq!(
+            res,
+            "{\"Ok\":{\"type\":\"list\",\"value\":[{\"type\":\"timestamp\",\"value\":\"2024-05-10T13:30:00+02:00\"},{\"type\":\"duration\",\"value\":\"5400.5s\"}]}}"
+        );
+
+        let out_of_range = serde_json::from_str::<PassableValue>(r#"{"type": "duration", "value": 99999999999999999}"#);
+        assert!(out_of_range.unwrap_err().to_string().contains("Duration out of range"));
+    }
+
+    #[test]
+    fn test_host_calls_are_memoized_per_evaluation() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "computed.daysSince('paywall_open') > 3 && computed.daysSince('paywall_open') < 10 && computed.daysSince('app_open') == 7",
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] }
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        assert_eq!(ctx.calls(), 2);
+    }
+
+    #[test]
+    fn test_volatile_host_calls_are_not_memoized() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "device.random() == 7 && device.random() == 7",
+            "device": { "random": [] },
+            "volatile": ["device.random"]
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        assert_eq!(ctx.calls(), 2);
+    }
+
+    #[test]
+    fn test_host_calls_are_resolved_concurrently() {
+        let ctx = Arc::new(ConcurrentContext {
+            in_flight: std::sync::atomic::AtomicUsize::new(0),
+            max_in_flight: std::sync::atomic::AtomicUsize::new(0),
+        });
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "computed.daysSince('paywall_open') + device.hoursSince('app_open') + computed.daysSince('app_install') == 3",
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] },
+            "device": { "hoursSince": [{"type": "string", "value": "event_name"}] }
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        assert_eq!(ctx.max_in_flight.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_evaluate_rules_shares_host_calls() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_rules(
+            r#"
+        {
+            "variables": { "map" : { "foo": {"type": "int", "value": 100} } },
+            "expressions": {
+                "recent": "computed.daysSince('paywall_open') < 3",
+                "week": "computed.daysSince('paywall_open') == 7 && foo == 100",
+                "broken": "bar == 1"
+            },
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] }
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(
+            res,
+            "{\"Ok\":{\"results\":{\"broken\":{\"Err\":{\"kind\":\"undeclared_reference\",\"message\":\"Undeclared reference to 'bar'\",\"identifier\":\"bar\"}},\"recent\":{\"Ok\":{\"type\":\"bool\",\"value\":false}},\"week\":{\"Ok\":{\"type\":\"bool\",\"value\":true}}}}}"
+        );
+        assert_eq!(ctx.calls(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_ordered_rules_stops_at_first_match() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_rules(
+            r#"
+        {
+            "variables": { "map" : { "foo": {"type": "int", "value": 100} } },
+            "expressions": [
+                {"id": "first", "expression": "foo > 100"},
+                {"id": "second", "expression": "foo == 100"},
+                {"id": "third", "expression": "true"}
+            ]
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        assert_eq!(
+            res,
+            "{\"Ok\":{\"results\":[{\"id\":\"first\",\"result\":{\"Ok\":{\"type\":\"bool\",\"value\":false}}},{\"id\":\"second\",\"result\":{\"Ok\":{\"type\":\"bool\",\"value\":true}}}],\"matched\":\"second\"}}"
+        );
+    }
+
+    #[test]
+    fn test_evaluate_ordered_rules_only_prefetches_the_first_rule() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let definition = serde_json::json!({
+            "variables": {"map": {}},
+            "computed": {"a": [], "b": []},
+            "expressions": [
+                {"id": "z", "expression": "computed.a() == 7"},
+                {"id": "a", "expression": "computed.b() == 7"}
+            ]
+        });
+        let res = evaluate_rules(definition.to_string(), ctx.clone());
+        let res: Result<RuleSetResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+        assert_eq!(res.unwrap().matched, Some("z".to_string()));
+        assert_eq!(ctx.calls(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_ordered_rules_keeps_their_order() {
+        let evaluate = |expressions: serde_json::Value| {
+            let definition = serde_json::json!({"variables": {"map": {}}, "expressions": expressions});
+            let res = evaluate_rules(definition.to_string(), Arc::new(CountingContext::new(PassableValue::Int(7))));
+            serde_json::from_str::<Result<RuleSetResult, EvaluationError>>(&res).unwrap()
+        };
+        let res = evaluate(serde_json::json!([
+            {"id": "z", "expression": "false"},
+            {"id": "a", "expression": "1"},
+            {"id": "y", "expression": "true"}
+        ]));
+        let RuleResults::Ordered(results) = res.unwrap().results else {
+            panic!("Ordered rules should have ordered results");
+        };
+        assert_eq!(
+            results.iter().map(|it| it.id.as_str()).collect::<Vec<_>>(),
+            vec!["z", "a", "y"]
+        );
+        assert_eq!(results[1].result, Ok(PassableValue::Int(1)));
+
+        let res = evaluate(serde_json::json!([
+            {"id": "z", "expression": "false"},
+            {"id": "z", "expression": "true"}
+        ]));
+        assert!(matches!(res, Err(EvaluationError::Deserialization { message }) if message.contains("Duplicate rule id: z")));
+    }
+
+    #[test]
+    fn test_compiled_expression_evaluated_multiple_times() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let compiled = CompiledExpression::new("foo + bar == 142".to_string());
+        let res = compiled.evaluate(
+            r#"
+        {
+            "variables": {
+             "map" : {
+                    "foo": {"type": "int", "value": 100},
+                    "bar": {"type": "int", "value": 42}
+            }}
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        let res = compiled.evaluate(
+            r#"
+        {
+            "variables": {
+             "map" : {
+                    "foo": {"type": "int", "value": 100},
+                    "bar": {"type": "int", "value": 41}
+            }}
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":false}}");
+    }
+
     #[test]
     fn test_parse_to_ast() {
         let expression = "device.daysSince(app_install) == 3";
@@ -807,7 +1911,8 @@ mod tests {
         println!("\nSerialized AST:");
         println!("{}", ast_json);
         // Deserialize back to JSONExpression
-        let deserialized_json_expr: JSONExpression = serde_json::from_str(&ast_json).unwrap();
+        let deserialized_json_expr: Result<JSONExpression, EvaluationError> = serde_json::from_str(&ast_json).unwrap();
+        let deserialized_json_expr = deserialized_json_expr.unwrap();
 
         // Convert back to original Expression
         let deserialized_expr: Expression = deserialized_json_expr.into();
@@ -819,4 +1924,1100 @@ mod tests {
         assert_eq!(parsed_expression, deserialized_expr);
         println!("\nOriginal and deserialized expressions are equal!");
     }
+
+    #[test]
+    fn test_parse_to_ast_with_invalid_expression_returns_error() {
+        let ast_json = parse_to_ast("user.name == \n  && true".to_string());
+        let res: Result<JSONExpression, EvaluationError> = serde_json::from_str(&ast_json).unwrap();
+        match res {
+            Err(EvaluationError::Parse { line, column, expected, .. }) => {
+                assert_eq!(line, Some(2));
+                assert_eq!(column, Some(3));
+                assert!(expected.contains(&"identifier".to_string()));
+                assert!(expected.contains(&"(".to_string()));
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_to_ast_with_invalid_literal_returns_error() {
+        let parse = |expression: &str| {
+            serde_json::from_str::<Result<JSONExpression, EvaluationError>>(&parse_to_ast(expression.to_string()))
+                .unwrap()
+        };
+        for expression in ["1 + 0x10", "9223372036854775808 > 1", "5 u", "'\\q' == ''", "b'\\xzz'", "'é\\uzz'"] {
+            assert!(
+                matches!(parse(expression), Err(EvaluationError::Parse { span: Some(_), .. })),
+                "{} should not parse",
+                expression
+            );
+        }
+        match parse("a &&\n  0x1F") {
+            Err(EvaluationError::Parse { line, column, message, .. }) => {
+                assert_eq!((line, column), (Some(2), Some(3)));
+                assert_eq!(message, "Hexadecimal literals are not supported: 0x1F");
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+        for expression in ["-9223372036854775808", "99999999999999999999.5", "1e300", "r'\\q'", "'\\n' + \"\\u00e9\""] {
+            assert!(parse(expression).is_ok(), "{} should parse", expression);
+        }
+    }
+
+    #[test]
+    fn test_analyze_expression() {
+        let res = analyze_expression(
+            "user.should_display && computed.daysSinceEvent(\"session_start\") > device.minimum_days \
+             && user.tags.exists(tag, tag == \"beta\") && isPremium(user.plan)"
+                .to_string(),
+            vec![],
+        );
+        let res: Result<analysis::ExpressionAnalysis, EvaluationError> = serde_json::from_str(&res).unwrap();
+        let analysis = res.unwrap();
+        assert_eq!(analysis.variables, vec!["user".to_string()]);
+        assert_eq!(
+            analysis.members,
+            vec!["device.minimum_days", "user.plan", "user.should_display", "user.tags"]
+        );
+        assert_eq!(analysis.computed, vec!["daysSinceEvent".to_string()]);
+        assert_eq!(analysis.device, vec!["minimum_days".to_string()]);
+        assert_eq!(
+            analysis.functions[0],
+            analysis::FunctionReference {
+                name: "daysSinceEvent".to_string(),
+                target: Some("computed".to_string()),
+                args: vec![Some(PassableValue::String("session_start".to_string()))],
+            }
+        );
+        assert_eq!(analysis.functions[2].args, vec![None]);
+        assert_eq!(analysis.unknown_functions, vec!["isPremium".to_string()]);
+        assert!(analysis.uses_unknown_functions);
+
+        // Properties of the declared namespaces are listed by object, like the `computed` and `device` ones
+        let res = analyze_expression(
+            "subscription.daysLeft() > 3 && subscription.plan == 'pro' && user.vip".to_string(),
+            vec!["subscription".to_string(), "session".to_string()],
+        );
+        let res: Result<analysis::ExpressionAnalysis, EvaluationError> = serde_json::from_str(&res).unwrap();
+        let analysis = res.unwrap();
+        assert_eq!(analysis.variables, vec!["user".to_string()]);
+        assert_eq!(
+            analysis.namespaces,
+            std::collections::BTreeMap::from([("subscription".to_string(), vec!["daysLeft".to_string(), "plan".to_string()])])
+        );
+        assert_eq!(analysis.functions[0].target, Some("subscription".to_string()));
+        assert!(!analysis.uses_unknown_functions);
+    }
+
+    #[test]
+    fn test_analyze_ast_matches_expression() {
+        let expression = "device.osVersion.startsWith(\"17\") || computed.isSubscribed()";
+        let ast: Result<JSONExpression, EvaluationError> =
+            serde_json::from_str(&parse_to_ast(expression.to_string())).unwrap();
+        let from_ast = analyze_ast(serde_json::to_string(&ast.unwrap()).unwrap(), vec![]);
+        assert_eq!(from_ast, analyze_expression(expression.to_string(), vec![]));
+        assert!(from_ast.contains("\"uses_unknown_functions\":false"));
+    }
+
+    #[test]
+    fn test_ast_to_expression_round_trip() {
+        let expressions = [
+            "a ? b : c ? d : e",
+            "(a ? b : c) ? d : e",
+            "a ? (b ? c : d) : e",
+            "(a || b) && !(c || d)",
+            "a || b && c",
+            "(a + b) * c - (d - e) / f % g",
+            "a - (b - c)",
+            "(a < b) == (c < d)",
+            "x in [1, -2, 3.5, 1e100] && -(-x) == - 5 && -- -5 == 5",
+            "!!x.y && !(!x)",
+            "user.profile[\"name\"].startsWith(\"a\") && size(user.tags) > 0",
+            "(a + b).size()",
+            "Message{name: \"x\", inner: Inner{value: [1, 2]}}.name",
+            "{\"key\": {1: null, 2: true}}[\"key\"][1] == null",
+            "\"quote \\\" backslash \\\\ tab \\t line \\n bell \\a é \\u0001\"",
+            "b\"\\x00\\xffbytes\\x22\\x5c\\101\" == b'abc'",
+            "computed.daysSince(\"event\") > 3 ? device.osVersion : \"unknown\"",
+        ];
+        for expression in expressions {
+            let parsed = parse(expression).unwrap();
+            let ast: JSONExpression = parsed.clone().into();
+            let res = ast_to_expression(serde_json::to_string(&ast).unwrap());
+            let res: Result<String, EvaluationError> = serde_json::from_str(&res).unwrap();
+            let printed = res.unwrap();
+            assert_eq!(parse(&printed).unwrap(), parsed, "{} printed as {}", expression, printed);
+        }
+        let res = ast_to_expression(
+            serde_json::to_string(&JSONExpression::from(parse("(a ? b : c) && (d + e) * f").unwrap())).unwrap(),
+        );
+        assert_eq!(res, "{\"Ok\":\"(a ? b : c) && (d + e) * f\"}");
+    }
+
+    #[test]
+    fn test_ast_to_expression_with_uint_returns_unsupported_error() {
+        let ast = JSONExpression::Atom(crate::ast::JSONAtom::UInt(5));
+        let res = ast_to_expression(serde_json::to_string(&ast).unwrap());
+        let res: Result<String, EvaluationError> = serde_json::from_str(&res).unwrap();
+        assert!(matches!(res, Err(EvaluationError::Unsupported { .. })));
+    }
+
+    fn limit_exceeded(res: &str) -> Option<String> {
+        let res: Result<PassableValue, EvaluationError> = serde_json::from_str(res).unwrap();
+        match res {
+            Err(EvaluationError::LimitExceeded { limit, .. }) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_evaluation_limits() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let evaluate = |expression: &str, limits: &str| {
+            evaluate_with_context(
+                format!(
+                    r#"{{"variables": {{"map": {{}}}}, "expression": {:?}, "limits": {}}}"#,
+                    expression, limits
+                ),
+                ctx.clone(),
+            )
+        };
+        let expression = "size([1, 2, 3, 4].map(x, [x, x, x].map(y, x * y))) == 4";
+        assert_eq!(
+            evaluate(expression, "{}"),
+            "{\"Ok\":{\"type\":\"bool\",\"value\":true}}"
+        );
+        assert_eq!(limit_exceeded(&evaluate(expression, r#"{"max_steps": 50}"#)), Some("max_steps".to_string()));
+        assert_eq!(limit_exceeded(&evaluate(expression, r#"{"max_steps": 500}"#)), None);
+        assert_eq!(
+            limit_exceeded(&evaluate("a ? (b ? (c ? 1 : 2) : 3) : 4", r#"{"max_depth": 3}"#)),
+            Some("max_depth".to_string())
+        );
+        assert_eq!(
+            limit_exceeded(&evaluate("'abc' + 'def' == 'abcdef'", r#"{"max_string_length": 5}"#)),
+            Some("max_string_length".to_string())
+        );
+        assert_eq!(
+            limit_exceeded(&evaluate("[1, 2] + [3, 4] + [5]", r#"{"max_list_size": 4}"#)),
+            Some("max_list_size".to_string())
+        );
+        // Errors caught by `maybe` still fail the evaluation when they come from a limit
+        assert_eq!(
+            limit_exceeded(&evaluate("[0].maybe([1, 2].map(x, x), 1)", r#"{"max_steps": 3}"#)),
+            Some("max_steps".to_string())
+        );
+    }
+
+    #[test]
+    fn test_evaluation_errors_instead_of_panics() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        for expression in [
+            "1 / 0",
+            "7 % 0",
+            "uint(1) / uint(0)",
+            "(-9223372036854775807 - 1) / -1",
+            "(-9223372036854775807 - 1) % -1",
+            "maybe(1, 2)",
+            "[1].maybe(2)",
+            "[1].map(x)",
+            "all([1])",
+            "has()",
+        ] {
+            let res = evaluate_with_context(
+                format!(r#"{{"variables": {{"map": {{}}}}, "expression": {:?}}}"#, expression),
+                ctx.clone(),
+            );
+            let res: Result<PassableValue, EvaluationError> = serde_json::from_str(&res).unwrap();
+            assert!(
+                matches!(res, Err(EvaluationError::FunctionError { .. })),
+                "{} should fail with a function error, got {:?}",
+                expression,
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_rules_limits_each_rule() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let res = evaluate_rules(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expressions": {"c": "1 + 1 + 1", "a": "1 + 1 + 1", "b": "computed.a() + computed.b()"},
+            "computed": { "a": [], "b": [] },
+            "limits": { "max_steps": 5, "max_host_calls": 1 }
+        }
+        "#
+            .to_string(),
+            ctx,
+        );
+        let res: Result<RuleSetResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+        let RuleResults::Named(results) = res.unwrap().results else {
+            panic!("Named rules should have named results");
+        };
+        assert_eq!(results["a"], Ok(PassableValue::Int(3)));
+        assert_eq!(results["c"], Ok(PassableValue::Int(3)));
+        assert!(matches!(&results["b"], Err(EvaluationError::LimitExceeded { limit, .. }) if limit == "max_host_calls"));
+    }
+
+    #[test]
+    fn test_host_call_limit() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "[1, 2, 3, 4].all(x, computed.daysSince(string(x)) == 7)",
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] },
+            "limits": { "max_host_calls": 3 }
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(limit_exceeded(&res), Some("max_host_calls".to_string()));
+        assert_eq!(ctx.calls(), 3);
+    }
+
+    #[test]
+    fn test_skipped_host_calls_are_not_prefetched() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : {} },
+            "expression": "false ? computed.daysSince('a') == 7 : computed.daysSince('b') == 7 || computed.daysSince('c') == 7",
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] },
+            "limits": { "max_host_calls": 1 }
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        assert_eq!(ctx.calls(), 1);
+    }
+
+    #[test]
+    fn test_macros_without_limits() {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : { "list": {"type": "list", "value": [{"type": "int", "value": 1}, {"type": "int", "value": 2}]} } },
+            "expression": "list.filter(x, x > 1) == [2] && list.exists_one(x, x == 1) && !list.exists(x, x > 5) && {'a': 1}.all(k, k == 'a') && list.maybe(missing, true) && has({'a': 1}.a) && !has({'a': 1}.b)"
+        }
+        "#
+                .to_string(),
+            ctx.clone(),
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+
+        // Macros and `maybe` can also be called with their target as first argument
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : { "list": {"type": "list", "value": [{"type": "int", "value": 1}, {"type": "int", "value": 2}]} } },
+            "expression": "maybe(0, missing, 1) == 1 && map(list, x, x * 2) == [2, 4] && all(list, x, x > 0)"
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+    }
+
+    #[test]
+    fn test_explain_evaluation() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::UInt(7)));
+        let res = evaluate_with_context(
+            r#"
+        {
+            "variables": { "map" : { "vip": {"type": "bool", "value": false} } },
+            "expression": "computed.daysSince('paywall_open') > 3 || vip ? 'show' : 'hide'",
+            "computed": { "daysSince": [{"type": "string", "value": "event_name"}] },
+            "explain": true
+        }
+        "#
+                .to_string(),
+            ctx,
+        );
+        let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+        assert_eq!(res["Ok"], serde_json::json!({"type": "string", "value": "show"}));
+        let explanation: Explanation = serde_json::from_value(res["explain"].clone()).unwrap();
+        let trace = explanation.trace.unwrap();
+        assert_eq!(trace.kind, "Ternary");
+        let condition = &trace.children[0];
+        assert_eq!(condition.kind, "Or");
+        assert_eq!(condition.children[0].expression, Some("computed.daysSince(\"paywall_open\") > 3".to_string()));
+        assert_eq!(condition.children[0].value, Some(PassableValue::Bool(true)));
+        assert!(condition.children[1].skipped);
+        assert_eq!(trace.children[1].value, Some(PassableValue::String("show".to_string())));
+        assert!(trace.children[2].skipped);
+        assert_eq!(
+            explanation.host_calls,
+            vec![HostCall {
+                object: "computed".to_string(),
+                name: "daysSince".to_string(),
+                args: vec![PassableValue::String("paywall_open".to_string())],
+                result: "{\"type\":\"uint\",\"value\":7}".to_string(),
+            }]
+        );
+    }
+
+    fn partial_residual(expression: &str, unknowns: &str, host: Arc<dyn HostContext>) -> Result<String, EvaluationError> {
+        let res = partial_evaluate(
+            format!(
+                r#"
+        {{
+            "variables": {{ "map" : {{
+                "vip": {{"type": "bool", "value": true}},
+                "limit": {{"type": "int", "value": 2}},
+                "user": {{"type": "map", "value": {{"name": {{"type": "string", "value": "a"}}}}}}
+            }} }},
+            "expression": {},
+            "computed": {{ "daysSince": [{{"type": "string", "value": "event_name"}}] }},
+            "unknowns": {}
+        }}
+        "#,
+                serde_json::to_string(expression).unwrap(),
+                unknowns
+            ),
+            host,
+        );
+        let res: Result<PartialResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+        res.map(|it| match it {
+            PartialResult::Value(value) => serde_json::to_string(&value).unwrap(),
+            PartialResult::Residual(ast) => printer::print(&ast.into()).unwrap(),
+        })
+    }
+
+    #[test]
+    fn test_partial_evaluation() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let unknowns = r#"["computed.daysSince", "user.lastPurchase"]"#;
+        let residual = |expression: &str| partial_residual(expression, unknowns, ctx.clone());
+        assert_eq!(
+            residual("computed.daysSince('paywall_open') < limit + 1 && vip"),
+            Ok("computed.daysSince(\"paywall_open\") < 3".to_string())
+        );
+        assert_eq!(
+            residual("vip || computed.daysSince('paywall_open') < 3"),
+            Ok("{\"type\":\"bool\",\"value\":true}".to_string())
+        );
+        assert_eq!(
+            residual("user.name == 'a' ? user.lastPurchase > limit : false"),
+            Ok("user.lastPurchase > 2".to_string())
+        );
+        assert_eq!(
+            residual("[1, 2].exists(i, i > limit - 1 && i < user.lastPurchase)"),
+            Ok("[1, 2].exists(i, i > 1 && i < user.lastPurchase)".to_string())
+        );
+        // Unknown host properties are never called
+        assert_eq!(ctx.calls(), 0);
+    }
+
+    #[test]
+    fn test_partial_evaluation_errors() {
+        let ctx = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let unknowns = r#"["user"]"#;
+        // The right side of `||` is skipped if the unknown is truthy, so its error is kept in the residual
+        assert_eq!(
+            partial_residual("user.vip || missing", unknowns, ctx.clone()),
+            Ok("user.vip || missing".to_string())
+        );
+        // Both sides of `&&` are evaluated, so the residual is kept even if the other side is false
+        assert_eq!(
+            partial_residual("!vip && user.lastPurchase.missing", unknowns, ctx.clone()),
+            Ok("false && user.lastPurchase.missing".to_string())
+        );
+        assert!(matches!(
+            partial_residual("missing + user.lastPurchase", unknowns, ctx.clone()),
+            Err(EvaluationError::UndeclaredReference { .. })
+        ));
+        // Known host properties are still resolved
+        assert_eq!(
+            partial_residual("computed.daysSince('paywall_open') > limit && user.vip", unknowns, ctx.clone()),
+            Ok("!!user.vip".to_string())
+        );
+        assert_eq!(ctx.calls(), 1);
+        // Branches which may be skipped keep their host calls and errors in the residual
+        assert_eq!(
+            partial_residual("user.a || computed.daysSince('a') > limit", unknowns, ctx.clone()),
+            Ok("user.a || computed.daysSince(\"a\") > 2".to_string())
+        );
+        assert_eq!(
+            partial_residual("user.a ? computed.daysSince('b') : false", unknowns, ctx.clone()),
+            Ok("user.a ? computed.daysSince(\"b\") : false".to_string())
+        );
+        assert_eq!(ctx.calls(), 1);
+        assert_eq!(
+            partial_residual("user.a || 1 / 0 == 1", unknowns, ctx.clone()),
+            Ok("user.a || 1 / 0 == 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_expression() {
+        let declarations = r#"
+        {
+            "variables": {
+                "user": {"object": {"should_display": "bool", "name": "string", "purchases": {"list": "int"}}},
+                "limit": "int"
+            },
+            "computed": { "daysSince": {"args": ["string"], "result": "int"} }
+        }
+        "#;
+        let check = |expression: &str| -> checker::CheckResult {
+            let res = check_expression(expression.to_string(), declarations.to_string());
+            let res: Result<checker::CheckResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+            res.unwrap()
+        };
+
+        let valid = check("user.should_display && computed.daysSince('paywall_open') < limit");
+        assert_eq!(valid.errors, vec![]);
+        assert_eq!(valid.result_type, checker::CelType::Bool);
+        let mapped = check("user.purchases.map(p, p * 2.0)");
+        assert_eq!(mapped.errors, vec![]);
+        assert_eq!(mapped.result_type, checker::CelType::List(Box::new(checker::CelType::Float)));
+
+        let errors = |expression: &str| -> Vec<(checker::CheckErrorKind, String)> {
+            check(expression).errors.into_iter().map(|it| (it.kind, it.expression)).collect()
+        };
+        assert_eq!(
+            errors("user.shouldDisplay"),
+            vec![(checker::CheckErrorKind::UndeclaredReference, "user.shouldDisplay".to_string())]
+        );
+        assert_eq!(
+            errors("computed.daysSince() > 1 && computed.other(1)"),
+            vec![
+                (checker::CheckErrorKind::WrongArity, "computed.daysSince()".to_string()),
+                (checker::CheckErrorKind::UnknownFunction, "computed.other(1)".to_string()),
+            ]
+        );
+        assert_eq!(
+            errors("user.name + limit > 1 || startsWith(limit, 'a')"),
+            vec![
+                (checker::CheckErrorKind::TypeMismatch, "user.name + limit".to_string()),
+                (checker::CheckErrorKind::TypeMismatch, "startsWith(limit, \"a\")".to_string()),
+            ]
+        );
+        // Errors of the first argument of `maybe` are recovered at runtime
+        assert_eq!(errors("[0].maybe(user.missing, 1)"), vec![]);
+    }
+
+    #[test]
+    fn test_check_host_properties_on_their_object() {
+        let declarations = r#"
+        {
+            "variables": {},
+            "computed": {
+                "locale": {"args": [], "result": "string"},
+                "size": {"args": [], "result": "int"}
+            }
+        }
+        "#;
+        let errors = |expression: &str| -> Vec<(checker::CheckErrorKind, String)> {
+            let res = check_expression(expression.to_string(), declarations.to_string());
+            let res: Result<checker::CheckResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+            res.unwrap().errors.into_iter().map(|it| (it.kind, it.expression)).collect()
+        };
+        assert_eq!(errors("computed.locale() == 'en' && computed.size() > 1"), vec![]);
+        // Like at runtime, properties are not callable without their object and do not shadow functions
+        assert_eq!(
+            errors("locale() == 'en'"),
+            vec![(checker::CheckErrorKind::UnknownFunction, "locale()".to_string())]
+        );
+        assert_eq!(errors("size('abc') == 3"), vec![]);
+    }
+
+    #[test]
+    fn test_check_namespace_properties() {
+        let declarations = r#"
+        {
+            "variables": {"user": {"object": {"name": "string"}}},
+            "namespaces": {
+                "subscription": {"daysLeft": {"args": [], "result": "int"}},
+                "user": {"daysSinceSignup": {"args": [], "result": "int"}}
+            }
+        }
+        "#;
+        let errors = |expression: &str| -> Vec<(checker::CheckErrorKind, String)> {
+            let res = check_expression(expression.to_string(), declarations.to_string());
+            let res: Result<checker::CheckResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+            res.unwrap().errors.into_iter().map(|it| (it.kind, it.expression)).collect()
+        };
+        assert_eq!(errors("subscription.daysLeft() > 3 && user.daysSinceSignup() < 7"), vec![]);
+        // The namespace of a variable does not hide its keys
+        assert_eq!(errors("user.name == 'Ada' && user.daysSinceSignup() > 1"), vec![]);
+        assert_eq!(
+            errors("subscription.daysLeft() > 'soon'")[0].0,
+            checker::CheckErrorKind::TypeMismatch
+        );
+        assert_eq!(
+            errors("subscription.renewsAt() > 3"),
+            vec![(checker::CheckErrorKind::UnknownFunction, "subscription.renewsAt()".to_string())]
+        );
+        assert_eq!(
+            errors("plan.tier() == 1"),
+            vec![
+                (checker::CheckErrorKind::UndeclaredReference, "plan".to_string()),
+                (checker::CheckErrorKind::UnknownFunction, "plan.tier()".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_library_functions_are_registered() {
+        let mut ctx = Context::default();
+        add_library_functions(&mut ctx);
+        // `now` is added with the time of the evaluation
+        ctx.add_function("now", |ftx: &FunctionContext| datetime::now(ftx, None));
+        strings::add_functions(&mut ctx);
+        let names = analysis::BUILTIN_FUNCTIONS
+            .iter()
+            .chain(library::library_functions().iter().map(|it| &it.name));
+        for name in names {
+            assert!(eval::has_function(&ctx, &Arc::new(name.to_string())), "{} is not registered", name);
+        }
+    }
+
+    #[test]
+    fn test_check_expression_with_invalid_declarations_returns_error() {
+        let res = check_expression("true".to_string(), r#"{"variables": {"a": "number"}}"#.to_string());
+        let res: Result<checker::CheckResult, EvaluationError> = serde_json::from_str(&res).unwrap();
+        assert!(matches!(res, Err(EvaluationError::Deserialization { .. })));
+    }
+
+    fn optimized(expression: &str) -> String {
+        let ast = parse_to_ast(expression.to_string());
+        let ast: Result<JSONExpression, EvaluationError> = serde_json::from_str(&ast).unwrap();
+        let optimized = optimize_ast(serde_json::to_string(&ast.unwrap()).unwrap());
+        let optimized: Result<JSONExpression, EvaluationError> = serde_json::from_str(&optimized).unwrap();
+        printer::print(&optimized.unwrap().into()).unwrap()
+    }
+
+    #[test]
+    fn test_optimize_ast() {
+        assert_eq!(optimized("true && (x > 3)"), "x > 3");
+        assert_eq!(optimized("size([1, 2, 3]) > 0 && !!(!!vip)"), "!!vip");
+        assert_eq!(optimized("!!(x > 1 + 2) || false"), "x > 3");
+        assert_eq!(optimized("false || name"), "name");
+        assert_eq!(optimized("1 > 2 ? a : [1, 2].map(i, i * 2)"), "[2, 4]");
+        assert_eq!(optimized("vip ? true : false"), "!!vip");
+        assert_eq!(optimized("!!items.filter(i, i > 10 - 5)"), "!!items.filter(i, i > 5)");
+        // `&&` evaluates both sides, so a `false` side cannot drop an expression which might fail
+        assert_eq!(optimized("false && computed.daysSince('x') > 3"), "false && computed.daysSince(\"x\") > 3");
+        // `name` is returned as is by `||` when it is truthy, so it cannot be converted to a boolean
+        assert_eq!(optimized("name || false"), "name || false");
+        // Failing constants are kept so they fail when evaluated
+        assert_eq!(optimized("vip && 1 / 0 > 1"), "vip && 1 / 0 > 1");
+        assert_eq!(
+            optimized("(-9223372036854775807 - 1) / -1 + (-9223372036854775807 - 1) % -1"),
+            "-9223372036854775808 / -1 + -9223372036854775808 % -1"
+        );
+        assert_eq!(optimized("(6 - 1) / -1 + 7 % -2"), "-4");
+        assert_eq!(optimized("[1 / (1 / 0)].exists(i, i > 2 / 2)"), "[1 / (1 / 0)].exists(i, i > 1)");
+    }
+
+    fn evaluate_expression(expression: &str) -> String {
+        let ctx = Arc::new(TestContext {
+            map: HashMap::new(),
+        });
+        let definition = serde_json::json!({
+            "variables": {"map": {
+                "device": {"type": "map", "value": {
+                    "appVersion": {"type": "string", "value": "10.0.1"},
+                    "osVersion": {"type": "string", "value": "17.4"}
+                }}
+            }},
+            "expression": expression
+        });
+        evaluate_with_context(definition.to_string(), ctx)
+    }
+
+    #[test]
+    fn test_version_functions() {
+        let ok_true = "{\"Ok\":{\"type\":\"bool\",\"value\":true}}";
+        assert_eq!(evaluate_expression("versionCompare('10.0', '9.1') == 1"), ok_true);
+        assert_eq!(evaluate_expression("device.appVersion.versionAtLeast('2.4')"), ok_true);
+        assert_eq!(evaluate_expression("versionAtLeast(device.osVersion, '17.4.0')"), ok_true);
+        assert_eq!(evaluate_expression("!device.osVersion.versionAtLeast('17.4.1')"), ok_true);
+        assert_eq!(
+            evaluate_expression("semver('v1.2.3-beta.2+exp.sha.5114f85') == {'major': 1, 'minor': 2, 'patch': 3, 'prerelease': 'beta.2', 'build': 'exp.sha.5114f85'}"),
+            ok_true
+        );
+        // Pre-release versions come before the release, build metadata is ignored
+        assert_eq!(
+            evaluate_expression(
+                "versionCompare('1.0.0-alpha', '1.0.0-alpha.1') == -1 && versionCompare('1.0.0-alpha.beta', '1.0.0-beta') == -1 \
+                && versionCompare('1.0.0-beta.2', '1.0.0-beta.11') == -1 && versionCompare('1.0.0-rc.1', '1.0.0') == -1 \
+                && versionCompare('1.0.0+20130313', '1.0.0+exp') == 0"
+            ),
+            ok_true
+        );
+        let res: Result<PassableValue, EvaluationError> =
+            serde_json::from_str(&evaluate_expression("versionAtLeast('1.x', '1.0')")).unwrap();
+        assert!(matches!(res, Err(EvaluationError::FunctionError { .. })));
+        assert_eq!(evaluate_expression("semver('9223372036854775807.0.0').major == 9223372036854775807"), ok_true);
+        for version in ["9223372036854775808.0.0", "18446744073709551615.0.0"] {
+            let res: Result<PassableValue, EvaluationError> =
+                serde_json::from_str(&evaluate_expression(&format!("semver('{}').major", version))).unwrap();
+            assert!(matches!(res, Err(EvaluationError::FunctionError { message, .. }) if message.contains("Invalid version")));
+        }
+    }
+
+    #[test]
+    fn test_hashing_functions() {
+        assert_eq!(hashing::murmur3_32(b"", 0), 0);
+        assert_eq!(hashing::murmur3_32(b"", 1), 0x514e28b7);
+        assert_eq!(hashing::murmur3_32(b"hello", 0), 0x248bfa47);
+        assert_eq!(hashing::murmur3_32(b"The quick brown fox jumps over the lazy dog", 0), 0x2e4ff723);
+        assert_eq!(hashing::fnv1a_64(b""), 0xcbf29ce484222325);
+        assert_eq!(hashing::fnv1a_64(b"foobar"), 0x85944171f73967e8);
+
+        let ok_true = "{\"Ok\":{\"type\":\"bool\",\"value\":true}}";
+        assert_eq!(evaluate_expression("murmur3('hello') == 613153351"), ok_true);
+        // Assignments must never change, since they decide which variant users see
+        assert_eq!(
+            evaluate_expression(
+                "bucket('user_1', 'experiment_42', 100) == 51 && bucket('user_2', 'experiment_42', 100) == 80 \
+                && bucket(42, 'experiment_42', 100) == bucket('42', 'experiment_42', 100)"
+            ),
+            ok_true
+        );
+        assert_eq!(
+            evaluate_expression(
+                "percentRollout('user_1', 'new_paywall', 0.38) && !percentRollout('user_1', 'new_paywall', 0.37) \
+                && percentRollout(42, 'new_paywall', 12) && !percentRollout('user_2', 'new_paywall', 98)"
+            ),
+            ok_true
+        );
+        let res: Result<PassableValue, EvaluationError> =
+            serde_json::from_str(&evaluate_expression("bucket('user_1', 'experiment_42', 0)")).unwrap();
+        assert!(matches!(res, Err(EvaluationError::FunctionError { .. })));
+    }
+
+    #[test]
+    fn test_string_extension() {
+        let evaluate = |expression: &str, extensions: serde_json::Value| -> String {
+            let definition = serde_json::json!({
+                "variables": {"map": {
+                    "device": {"type": "map", "value": {"locale": {"type": "string", "value": "en_US"}}},
+                    "email": {"type": "string", "value": " Jane.Doe@Example.com\n"}
+                }},
+                "expression": expression,
+                "extensions": extensions
+            });
+            evaluate_with_context(definition.to_string(), Arc::new(TestContext { map: HashMap::new() }))
+        };
+        let strings = serde_json::json!(["strings"]);
+        let ok_true = "{\"Ok\":{\"type\":\"bool\",\"value\":true}}";
+        assert_eq!(evaluate("email.trim().lowerAscii().endsWith('@example.com')", strings.clone()), ok_true);
+        assert_eq!(
+            evaluate(
+                "device.locale.split('_')[1] == 'US' && device.locale.substring(0, 2) == 'en' \
+                && device.locale.indexOf('_') == 2 && 'héllo'.charAt(1) == 'é' && 'héllo'.indexOf('l', 3) == 3 \
+                && 'a-b-c'.replace('-', '_', 1) == 'a_b-c' && ['a', 'b'].join(', ') == 'a, b' \
+                && upperAscii('é-x') == 'é-X' && 'a,b,c'.split(',', 2) == ['a', 'b,c'] \
+                && 'abc'.split('') == ['a', 'b', 'c'] && 'héllo'.split('', 3) == ['h', 'é', 'llo'] && ''.split('') == []",
+                strings.clone()
+            ),
+            ok_true
+        );
+        assert_eq!(
+            evaluate("'%s has %d items costing %.2f (%x)'.format(['cart', 3, 9.5, 255])", strings.clone()),
+            "{\"Ok\":{\"type\":\"string\",\"value\":\"cart has 3 items costing 9.50 (ff)\"}}"
+        );
+        for expression in ["'abc'.substring(2, 5)", "'%.70000f'.format([1.5])"] {
+            let res: Result<PassableValue, EvaluationError> =
+                serde_json::from_str(&evaluate(expression, strings.clone())).unwrap();
+            assert!(matches!(res, Err(EvaluationError::FunctionError { .. })), "{}", expression);
+        }
+        // The extension is only available when it is enabled
+        let res: Result<PassableValue, EvaluationError> =
+            serde_json::from_str(&evaluate("email.trim()", serde_json::json!([]))).unwrap();
+        assert!(matches!(res, Err(EvaluationError::UndeclaredReference { .. })));
+    }
+
+    #[test]
+    fn test_date_functions() {
+        let evaluate = |expression: &str| -> String {
+            let definition = serde_json::json!({
+                "variables": {"map": {
+                    "device": {"type": "map", "value": {
+                        "installDate": {"type": "timestamp", "value": "2024-06-11T08:00:00Z"}
+                    }}
+                }},
+                "expression": expression,
+                // A Saturday
+                "now": "2024-06-15T20:30:00Z"
+            });
+            evaluate_with_context(definition.to_string(), Arc::new(TestContext { map: HashMap::new() }))
+        };
+        let ok_true = "{\"Ok\":{\"type\":\"bool\",\"value\":true}}";
+        assert_eq!(
+            evaluate(
+                "daysBetween(device.installDate, now()) == 4 && now() - device.installDate > duration('72h') \
+                && now().getDayOfWeek() == 6 && now().getDayOfWeek('+05:00') == 0 && now().getHours('-07:00') == 13 \
+                && now().getFullYear() == 2024 && now().getMonth() == 5 && now().getDate() == 15 \
+                && now().getDayOfMonth() == 14 && now().getDayOfYear() == 166 && now().getMinutes('UTC+05:30') == 0 \
+                && duration('90m').getHours() == 1 && duration('90m').getMinutes() == 90"
+            ),
+            ok_true
+        );
+        for zone in ["Europe/Paris", "é", "+1é2", "UTC-é"] {
+            let res: Result<PassableValue, EvaluationError> =
+                serde_json::from_str(&evaluate(&format!("now().getHours('{}')", zone))).unwrap();
+            assert!(matches!(res, Err(EvaluationError::FunctionError { .. })), "{}", zone);
+        }
+        // `now()` is never folded, since it changes between evaluations
+        assert_eq!(optimized("now() > timestamp('2024-01-01T00:00:00Z')"), "now() > timestamp(\"2024-01-01T00:00:00Z\")");
+    }
+
+    struct NativeTestContext;
+
+    #[async_trait]
+    impl NativeHostContext for NativeTestContext {
+        async fn computed_property(&self, name: String, args: ValueTree) -> Result<ValueTree, NativeError> {
+            let value = match (name.as_str(), PassableValue::try_from(&args)) {
+                ("daysSinceEvent", Ok(PassableValue::List(args))) if args == [PassableValue::String("event".to_string())] => {
+                    PassableValue::Int(7)
+                }
+                ("offline", _) => {
+                    return Err(NativeError::HostError { reason: "No connection".to_string(), property: name })
+                }
+                // Not representable in JSON
+                ("ratio", _) => PassableValue::Float(f64::NAN),
+                _ => PassableValue::Null,
+            };
+            Ok(ValueTree::from(&value))
+        }
+
+        async fn device_property(&self, _name: String, _args: ValueTree) -> Result<ValueTree, NativeError> {
+            Ok(ValueTree::from(&PassableValue::Null))
+        }
+
+        async fn property(&self, namespace: String, name: String, args: ValueTree) -> Result<ValueTree, NativeError> {
+            match namespace.as_str() {
+                "subscription" => Ok(ValueTree::from(&PassableValue::Int(12))),
+                "computed" => self.computed_property(name, args).await,
+                _ => self.device_property(name, args).await,
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_evaluation() {
+        let user = PassableValue::PMap(HashMap::from([
+            ("plan".to_string(), PassableValue::String("pro".to_string())),
+            ("seats".to_string(), PassableValue::List(vec![PassableValue::Int(1), PassableValue::UInt(2)])),
+        ]));
+        let variables = ValueTree::from(&PassableValue::PMap(HashMap::from([("user".to_string(), user)])));
+        assert_eq!(variables.root as usize, variables.nodes.len() - 1);
+        let context = || NativeExecutionContext {
+            variables: variables.clone(),
+            computed: HashMap::from([
+                (
+                    "daysSinceEvent".to_string(),
+                    ValueTree::from(&PassableValue::List(vec![PassableValue::String("event".to_string())])),
+                ),
+                ("offline".to_string(), ValueTree::from(&PassableValue::List(vec![]))),
+                ("ratio".to_string(), ValueTree::from(&PassableValue::List(vec![]))),
+            ]),
+            device: HashMap::new(),
+            namespaces: Some(HashMap::from([(
+                "subscription".to_string(),
+                HashMap::from([("daysLeft".to_string(), ValueTree::from(&PassableValue::List(vec![])))]),
+            )])),
+            options: NativeOptions {
+                now_millis: Some(1718483400000),
+                ..Default::default()
+            },
+        };
+        let host = Arc::new(NativeTestContext);
+        let result = evaluate_native(
+            "user.plan == 'pro' && size(user.seats) == 2 && computed.daysSinceEvent('event') > 3 \
+            ? [now(), duration('90m')] : []"
+                .to_string(),
+            context(),
+            host.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            PassableValue::try_from(&result),
+            serde_json::from_str(
+                r#"{"type": "list", "value": [
+                    {"type": "timestamp", "value": "2024-06-15T20:30:00Z"},
+                    {"type": "duration", "value": "5400s"}
+                ]}"#
+            )
+            .map_err(EvaluationError::deserialization)
+        );
+
+        let compiled = CompiledExpression::new("user.missing".to_string());
+        assert!(matches!(
+            compiled.evaluate_native(context(), host.clone()),
+            Err(NativeError::UndeclaredReference { .. })
+        ));
+        let result = evaluate_native("subscription.daysLeft() - 2".to_string(), context(), host.clone());
+        assert_eq!(result.as_ref().map(PassableValue::try_from), Ok(Ok(PassableValue::Int(10))));
+        // Host values are passed without going through JSON
+        let result = evaluate_native("computed.ratio() != computed.ratio()".to_string(), context(), host.clone());
+        assert_eq!(result.as_ref().map(PassableValue::try_from), Ok(Ok(PassableValue::Bool(true))));
+        assert_eq!(
+            evaluate_native("computed.offline()".to_string(), context(), host.clone()),
+            Err(NativeError::HostError {
+                reason: "The host failed to resolve computed.offline: No connection".to_string(),
+                property: "computed.offline".to_string(),
+            })
+        );
+        assert!(matches!(
+            evaluate_native("user.".to_string(), context(), host.clone()),
+            Err(NativeError::Parse { start: Some(5), .. })
+        ));
+        // Nodes can only reference the nodes before them, which rules out cycles
+        let cyclic = NativeExecutionContext {
+            variables: ValueTree {
+                nodes: vec![ValueNode::ListValue { items: vec![0] }],
+                root: 0,
+            },
+            ..context()
+        };
+        assert!(matches!(
+            evaluate_native("true".to_string(), cyclic, host),
+            Err(NativeError::Deserialization { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_evaluation() {
+        let host = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let definition = serde_json::json!({
+            "variables": {"map": {"user": {"type": "map", "value": {"id": {"type": "string", "value": "u1"}}}}},
+            "computed": {"daysSinceEvent": [{"type": "string", "value": "event"}]},
+            "expression": "computed.daysSinceEvent('install') > 3 && computed.daysSinceEvent(user.id) == 7"
+        });
+        let res = evaluate_with_context_async(definition.to_string(), host.clone()).await;
+        assert_eq!(res, "{\"Ok\":{\"type\":\"bool\",\"value\":true}}");
+        assert_eq!(host.calls(), 2);
+
+        // On WASM, the evaluation requests the properties it misses and runs again once they are resolved
+        let host = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let cache = PropertyCache::default();
+        let computed = Arc::new("computed".to_string());
+        let key = property_key(&computed, "daysSinceInstall", &[]);
+        let mut runs = 0;
+        let result = resolve_host_calls(&(Arc::new(JsonHost(host.clone())) as Arc<dyn PropertyHost>), &cache, &Budget::new(None), || {
+            runs += 1;
+            cache.get(&key).ok_or_else(|| {
+                let name = Arc::new("daysSinceInstall".to_string());
+                cache.pending.lock().unwrap().push((key.clone(), computed.clone(), name, vec![]));
+            })
+        })
+        .await;
+        assert_eq!(result, Ok(Ok(PassableValue::Int(7))));
+        assert_eq!((runs, host.calls()), (2, 1));
+    }
+
+    struct ObjectContext;
+
+    #[async_trait]
+    impl HostContext for ObjectContext {
+        async fn computed_property(&self, name: String, _args: String) -> String {
+            serde_json::to_string(&PassableValue::String(format!("computed.{}", name))).unwrap()
+        }
+
+        async fn device_property(&self, name: String, _args: String) -> String {
+            serde_json::to_string(&PassableValue::String(format!("device.{}", name))).unwrap()
+        }
+
+        async fn property(&self, namespace: String, name: String, _args: String) -> String {
+            serde_json::to_string(&PassableValue::String(format!("{}.{}", namespace, name))).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_host_property_objects() {
+        let evaluate_with = |expression: &str, host: Arc<dyn HostContext>| {
+            let definition = serde_json::json!({
+                "variables": {"map": {"user": {"type": "map", "value": {"plan": {"type": "string", "value": "pro"}}}}},
+                "computed": {"locale": [], "size": [], "maybe": []},
+                "device": {"locale": []},
+                "namespaces": {"user": {"daysLeft": []}},
+                "expression": expression
+            });
+            serde_json::from_str::<Result<PassableValue, EvaluationError>>(&evaluate_with_context(
+                definition.to_string(),
+                host,
+            ))
+            .unwrap()
+        };
+        let evaluate = |expression: &str| evaluate_with(expression, Arc::new(ObjectContext));
+        let string = |value: &str| Ok(PassableValue::String(value.to_string()));
+        assert_eq!(evaluate("computed.locale() + ' ' + device.locale()"), string("computed.locale device.locale"));
+        // Properties named like functions do not shadow them
+        assert_eq!(evaluate("computed.size() + ' ' + string(size([1, 2]))"), string("computed.size 2"));
+        assert_eq!(evaluate("computed.maybe() + [0].maybe(computed.missing(), '!')"), string("computed.maybe!"));
+        assert!(matches!(evaluate("device.missing()"), Err(EvaluationError::UndeclaredReference { .. })));
+
+        // Other objects are declared in the namespaces, next to the variable of the same name
+        assert_eq!(evaluate("user.plan + ' ' + user.daysLeft()"), string("pro user.daysLeft"));
+        let host = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        assert_eq!(
+            evaluate_with("user.daysLeft()", host),
+            Err(EvaluationError::HostError {
+                message: "The host failed to resolve user.daysLeft: No host object named user".to_string(),
+                property: "user.daysLeft".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_lazy_host_attributes() {
+        let evaluate_with = |expression: &str, host: Arc<dyn HostContext>| {
+            let definition = serde_json::json!({
+                "variables": {"map": {
+                    "device": {"type": "map", "value": {"locale": {"type": "string", "value": "en"}}},
+                    "user": {"type": "map", "value": {"plan": {"type": "string", "value": "pro"}}}
+                }},
+                "device": {"daysSince": [{"type": "string", "value": "event"}]},
+                "lazy": ["device.osVersion", "device.locale", "user.tier"],
+                "expression": expression
+            });
+            serde_json::from_str::<Result<PassableValue, EvaluationError>>(&evaluate_with_context(
+                definition.to_string(),
+                host,
+            ))
+            .unwrap()
+        };
+        let host = Arc::new(CountingContext::new(PassableValue::Int(7)));
+        let evaluate = |expression: &str| evaluate_with(expression, host.clone());
+        assert_eq!(evaluate("device.osVersion + device.osVersion"), Ok(PassableValue::Int(14)));
+        assert_eq!(host.calls(), 1);
+        // Lazy attributes are only resolved when accessed, and not at all when given in the variable
+        assert_eq!(evaluate("true || device.osVersion == 7"), Ok(PassableValue::Bool(true)));
+        assert_eq!(evaluate("device.locale"), Ok(PassableValue::String("en".to_string())));
+        assert_eq!(host.calls(), 1);
+        assert!(matches!(evaluate("device.missing"), Err(EvaluationError::UndeclaredReference { .. })));
+
+        // Declared lazy attributes exist without calling the host, and can be read with a constant key
+        assert_eq!(evaluate("has(device.osVersion) && has(device.locale)"), Ok(PassableValue::Bool(true)));
+        assert_eq!(evaluate("has(device.missing)"), Ok(PassableValue::Bool(false)));
+        assert_eq!(host.calls(), 1);
+        assert_eq!(evaluate("device['osVersion'] == 7"), Ok(PassableValue::Bool(true)));
+        assert_eq!(evaluate("device['locale']"), Ok(PassableValue::String("en".to_string())));
+        assert_eq!(host.calls(), 2);
+
+        // Lazy attributes declare their object, next to the variable of the same name
+        assert_eq!(
+            evaluate_with("user.plan + ' ' + user.tier", Arc::new(ObjectContext)),
+            Ok(PassableValue::String("pro user.tier".to_string()))
+        );
+    }
+
+    struct SlowContext;
+
+    #[async_trait]
+    impl HostContext for SlowContext {
+        async fn computed_property(&self, name: String, _args: String) -> String {
+            if name == "slow" {
+                futures::future::pending::<()>().await;
+            }
+            serde_json::to_string(&PassableValue::Int(7)).unwrap()
+        }
+
+        async fn device_property(&self, _name: String, _args: String) -> String {
+            futures::future::pending().await
+        }
+    }
+
+    #[test]
+    fn test_host_timeouts() {
+        let evaluate = |expression: &str, options: serde_json::Value| {
+            let mut definition = serde_json::json!({
+                "variables": {"map": {}},
+                "computed": {"slow": [], "fast": []},
+                "device": {"locale": []},
+                "expression": expression
+            });
+            definition.as_object_mut().unwrap().extend(options.as_object().unwrap().clone());
+            serde_json::from_str::<serde_json::Value>(&evaluate_with_context(definition.to_string(), Arc::new(SlowContext)))
+                .unwrap()
+        };
+        let res = evaluate(
+            "computed.slow() == 0 && computed.fast() == 7",
+            serde_json::json!({
+                "timeouts": {"host_call_millis": 20},
+                "fallbacks": {"computed.slow": {"type": "int", "value": 0}}
+            }),
+        );
+        assert_eq!(res["Ok"], serde_json::json!({"type": "bool", "value": true}));
+        assert_eq!(
+            res["timed_out"],
+            serde_json::json!([{"object": "computed", "name": "slow", "args": [], "fallback": true}])
+        );
+
+        // Without a fallback the property fails, unless `maybe` catches it
+        let res = evaluate("computed.slow()", serde_json::json!({"timeouts": {"host_call_millis": 20}}));
+        assert_eq!(res["Err"]["kind"], "host_error");
+        assert_eq!(res["Err"]["property"], "computed.slow");
+        let res = evaluate("[0].maybe(computed.slow(), 1)", serde_json::json!({"timeouts": {"host_call_millis": 20}}));
+        assert_eq!(res["Ok"], serde_json::json!({"type": "int", "value": 1}));
+
+        // The deadline of the evaluation is shared by all of its calls
+        let res = evaluate(
+            "[0].maybe(device.locale(), 'en') + [0].maybe(computed.slow(), 'x') == 'enx' && computed.fast() == 7",
+            serde_json::json!({"timeouts": {"evaluation_millis": 20}}),
+        );
+        assert_eq!(res["Ok"], serde_json::json!({"type": "bool", "value": true}));
+        assert_eq!(res["timed_out"].as_array().map(|it| it.len()), Some(2));
+        let res = evaluate("computed.fast()", serde_json::json!({"timeouts": {"evaluation_millis": 20}}));
+        assert_eq!(res.get("timed_out"), None);
+    }
+
+    #[test]
+    fn test_host_errors() {
+        let ctx = Arc::new(TestContext {
+            map: [
+                ("failing", r#"{"error": "Database is locked"}"#),
+                ("invalid", "not json"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        });
+        let evaluate = |expression: &str| {
+            let definition = serde_json::json!({
+                "variables": {"map": {}},
+                "computed": {"failing": [], "invalid": []},
+                "expression": expression
+            });
+            serde_json::from_str::<Result<PassableValue, EvaluationError>>(&evaluate_with_context(
+                definition.to_string(),
+                ctx.clone(),
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            evaluate("computed.failing()"),
+            Err(EvaluationError::HostError {
+                message: "The host failed to resolve computed.failing: Database is locked".to_string(),
+                property: "computed.failing".to_string(),
+            })
+        );
+        assert!(matches!(
+            evaluate("computed.invalid() == 1"),
+            Err(EvaluationError::HostError { property, .. }) if property == "computed.invalid"
+        ));
+        assert_eq!(evaluate("[0].maybe(computed.failing(), 1)"), Ok(PassableValue::Int(1)));
+    }
 }
@@ -0,0 +1,85 @@
+use crate::checker::CelType;
+use crate::models::Extension;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/**
+ * Signature of a function added by this library, including the functions of the extensions.
+ * The analysis, the optimizer and the checker all know these functions from the table below,
+ * so adding a function only means registering it in the context and declaring it here.
+ */
+pub(crate) struct LibraryFunction {
+    pub(crate) name: &'static str,
+    /// The extension enabling the function, if it is not available in every evaluation.
+    pub(crate) extension: Option<Extension>,
+    /// Types of the operands, starting with the target if the function is called as a method.
+    pub(crate) operands: Vec<CelType>,
+    /// Number of trailing operands which can be omitted.
+    pub(crate) optional: usize,
+    pub(crate) result: CelType,
+}
+
+/**
+ * Every function added by this library, with its signature.
+ */
+pub(crate) fn library_functions() -> &'static [LibraryFunction] {
+    static FUNCTIONS: OnceLock<Vec<LibraryFunction>> = OnceLock::new();
+    FUNCTIONS.get_or_init(|| {
+        use CelType::*;
+        let function = |name, extension, operands, optional, result| LibraryFunction {
+            name,
+            extension,
+            operands,
+            optional,
+            result,
+        };
+        let semver = Object(BTreeMap::from([
+            ("major".to_string(), Int),
+            ("minor".to_string(), Int),
+            ("patch".to_string(), Int),
+            ("prerelease".to_string(), String),
+            ("build".to_string(), String),
+        ]));
+        let strings = Some(Extension::Strings);
+        let mut functions = vec![
+            function("semver", None, vec![String], 0, semver),
+            function("versionCompare", None, vec![String, String], 0, Int),
+            function("versionAtLeast", None, vec![String, String], 0, Bool),
+            function("fnv1a64", None, vec![String], 0, UInt),
+            function("murmur3", None, vec![String], 0, UInt),
+            function("bucket", None, vec![Dyn, String, Int], 0, Int),
+            function("percentRollout", None, vec![Dyn, String, Dyn], 0, Bool),
+            function("now", None, vec![], 0, Timestamp),
+            function("daysBetween", None, vec![Timestamp, Timestamp], 0, Int),
+            function("lowerAscii", strings, vec![String], 0, String),
+            function("upperAscii", strings, vec![String], 0, String),
+            function("trim", strings, vec![String], 0, String),
+            function("split", strings, vec![String, String, Int], 1, List(Box::new(String))),
+            function("join", strings, vec![List(Box::new(String)), String], 1, String),
+            function("replace", strings, vec![String, String, String, Int], 1, String),
+            function("substring", strings, vec![String, Int, Int], 1, String),
+            function("indexOf", strings, vec![String, String, Int], 1, Int),
+            function("charAt", strings, vec![String, Int], 0, String),
+            function("format", strings, vec![String, List(Box::new(Dyn))], 0, String),
+        ];
+        for name in ["getFullYear", "getMonth", "getDate", "getDayOfMonth", "getDayOfYear", "getDayOfWeek"] {
+            functions.push(function(name, None, vec![Timestamp, String], 1, Int));
+        }
+        // Also the total length of a duration in the unit, in which case there is no time zone
+        for name in ["getHours", "getMinutes", "getSeconds", "getMilliseconds"] {
+            functions.push(function(name, None, vec![Dyn, String], 1, Int));
+        }
+        functions
+    })
+}
+
+/**
+ * Finds the signature of a function added by this library, if it is available with the given extensions.
+ * @param name The name of the function
+ * @param extensions The extensions the expression is evaluated with
+ */
+pub(crate) fn library_function(name: &str, extensions: &[Extension]) -> Option<&'static LibraryFunction> {
+    library_functions().iter().find(|it| {
+        it.name == name && it.extension.is_none_or(|extension| extensions.contains(&extension))
+    })
+}
@@ -0,0 +1,120 @@
+use crate::error::EvaluationError;
+use cel_parser::parse::{parse_bytes, parse_string, ParseError};
+
+/**
+ * Checks the literals of an expression which the parser accepts but then panics on, so that they fail with a
+ * `parse` error instead of aborting the host: integers out of range, hexadecimal integers, a `u` suffix separated
+ * from its integer by a space, and strings or bytes with invalid escape sequences.
+ * The literals are found the same way the parser tokenizes them, so `-` is part of an integer directly following it.
+ * @param expression The expression to check before parsing it
+ */
+pub(crate) fn check(expression: &str) -> Result<(), EvaluationError> {
+    let bytes = expression.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        let start = index;
+        match bytes[index] {
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                while index < bytes.len() && (bytes[index] == b'_' || bytes[index].is_ascii_alphanumeric()) {
+                    index += 1;
+                }
+                let prefix = &expression[start..index];
+                if matches!(prefix, "r" | "R" | "b" | "B") && matches!(bytes.get(index), Some(b'\'' | b'"')) {
+                    index = quoted(expression, start, index)?;
+                }
+            }
+            b'\'' | b'"' => index = quoted(expression, start, index)?,
+            b'0'..=b'9' => index = number(expression, start, index)?,
+            b'-' if bytes.get(index + 1).is_some_and(u8::is_ascii_digit) => {
+                index = number(expression, start, index + 1)?
+            }
+            _ => index += 1,
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Checks the string or bytes literal whose opening quote is at `quote`, i.e. `'\n'` or `b"\x00"`.
+ * @return The offset following the literal, or the end of the expression if the literal is not closed,
+ * which the parser reports itself
+ */
+fn quoted(expression: &str, start: usize, quote: usize) -> Result<usize, EvaluationError> {
+    let bytes = expression.as_bytes();
+    let mut index = quote + 1;
+    while index < bytes.len() && bytes[index] != bytes[quote] {
+        index += if bytes[index] == b'\\' { 2 } else { 1 };
+    }
+    if index >= bytes.len() {
+        return Ok(bytes.len());
+    }
+    let end = index + 1;
+    let literal = &expression[start..end];
+    let result = match bytes[start] {
+        b'b' | b'B' => parse_bytes(&expression[quote + 1..index]).map(|_| ()),
+        _ => parse_string(literal).map(|_| ()),
+    };
+    result.map(|_| end).map_err(|err| {
+        let message = match err {
+            ParseError::InvalidEscape { escape, .. } => format!("Invalid escape sequence {} in {}", escape, literal),
+            ParseError::InvalidUnicode { .. } => format!("Invalid unicode escape sequence in {}", literal),
+            _ => format!("Invalid literal {}", literal),
+        };
+        EvaluationError::invalid_literal(expression, start, end, message)
+    })
+}
+
+/**
+ * Checks the number literal whose digits start at `digits`, i.e. `42`, `-7`, `1.5e3` or `0x1F`.
+ * Floats never fail, as they round to infinity when out of range.
+ * @return The offset following the literal
+ */
+fn number(expression: &str, start: usize, digits: usize) -> Result<usize, EvaluationError> {
+    let bytes = expression.as_bytes();
+    let digits_end = |mut index: usize| {
+        while index < bytes.len() && bytes[index].is_ascii_digit() {
+            index += 1;
+        }
+        index
+    };
+    let is_digit = |index: usize| bytes.get(index).is_some_and(u8::is_ascii_digit);
+    let invalid = |end: usize, message: String| Err(EvaluationError::invalid_literal(expression, start, end, message));
+
+    if bytes[digits] == b'0'
+        && matches!(bytes.get(digits + 1), Some(b'x' | b'X'))
+        && bytes.get(digits + 2).is_some_and(u8::is_ascii_hexdigit)
+    {
+        let mut end = digits + 2;
+        while end < bytes.len() && bytes[end].is_ascii_hexdigit() {
+            end += 1;
+        }
+        return invalid(
+            end,
+            format!("Hexadecimal literals are not supported: {}", &expression[start..end]),
+        );
+    }
+    let mut end = digits_end(digits);
+    if bytes.get(end) == Some(&b'.') && is_digit(end + 1) {
+        end = digits_end(end + 1);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent = if matches!(bytes.get(end + 1), Some(b'+' | b'-')) { end + 2 } else { end + 1 };
+        if is_digit(exponent) {
+            return Ok(digits_end(exponent));
+        }
+    }
+    let literal = &expression[start..end];
+    if literal.contains('.') {
+        return Ok(end);
+    }
+    if bytes.get(end) == Some(&b' ') && matches!(bytes.get(end + 1), Some(b'u' | b'U')) {
+        return invalid(
+            end + 2,
+            format!("Unexpected space between {} and its unsigned suffix", literal),
+        );
+    }
+    match literal.parse::<i64>() {
+        Ok(_) => Ok(end),
+        Err(_) => invalid(end, format!("Integer literal out of range: {}", literal)),
+    }
+}
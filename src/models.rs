@@ -1,8 +1,11 @@
+use crate::ast::JSONExpression;
+use crate::error::EvaluationError;
 use crate::DisplayableValue;
 use cel_interpreter::objects::{Key, Map};
 use cel_interpreter::Value;
+use chrono::{DateTime, FixedOffset};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -10,7 +13,280 @@ pub(crate) struct ExecutionContext {
     pub(crate) variables: PassableMap,
     pub(crate) expression: String,
     pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
-    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    /// Host objects other than `computed` and `device`, mapped to the properties they declare,
+    /// i.e. `{"subscription": {"daysLeft": []}}`.
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    /// Return an explanation of the evaluation next to its result.
+    #[serde(default)]
+    pub(crate) explain: bool,
+    #[serde(flatten)]
+    pub(crate) options: EvaluationOptions
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct CompiledExecutionContext {
+    pub(crate) variables: PassableMap,
+    pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    /// Host objects other than `computed` and `device`, mapped to the properties they declare,
+    /// i.e. `{"subscription": {"daysLeft": []}}`.
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    #[serde(flatten)]
+    pub(crate) options: EvaluationOptions
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct RuleSetContext {
+    pub(crate) variables: PassableMap,
+    pub(crate) expressions: RuleSet,
+    pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    /// Host objects other than `computed` and `device`, mapped to the properties they declare,
+    /// i.e. `{"subscription": {"daysLeft": []}}`.
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    #[serde(flatten)]
+    pub(crate) options: EvaluationOptions
+}
+
+/**
+ * Expressions of a rule set, either a map of rule ids to expressions which are all evaluated,
+ * or an ordered list of rules which is evaluated until the first rule evaluating to `true`.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub(crate) enum RuleSet {
+    Named(HashMap<String, String>),
+    Ordered(Vec<Rule>),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct Rule {
+    pub(crate) id: String,
+    pub(crate) expression: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct RuleSetResult {
+    pub(crate) results: RuleResults,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) matched: Option<String>,
+}
+
+/**
+ * Results of the evaluated rules, keyed by their ids for a map of rules,
+ * or in the order of the rules for an ordered list of rules.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub(crate) enum RuleResults {
+    Named(BTreeMap<String, Result<PassableValue, EvaluationError>>),
+    Ordered(Vec<RuleResult>),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct RuleResult {
+    pub(crate) id: String,
+    pub(crate) result: Result<PassableValue, EvaluationError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct PartialExecutionContext {
+    pub(crate) variables: PassableMap,
+    pub(crate) expression: String,
+    pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    /// Host objects other than `computed` and `device`, mapped to the properties they declare,
+    /// i.e. `{"subscription": {"daysLeft": []}}`.
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    /// Variables, member paths or host properties whose value is not known yet,
+    /// i.e. `user`, `user.lastPurchase` or `computed.daysSince`.
+    pub(crate) unknowns: Vec<String>,
+    #[serde(flatten)]
+    pub(crate) options: EvaluationOptions
+}
+
+/**
+ * Result of a partial evaluation, either the value of the expression if it does not depend on the unknowns,
+ * or the residual expression which only references the unknowns.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) enum PartialResult {
+    #[serde(rename = "value")]
+    Value(PassableValue),
+    #[serde(rename = "residual")]
+    Residual(JSONExpression),
+}
+
+/**
+ * Result of an evaluation, serialized as the usual JSON `Result` with the metadata of the evaluation next to it
+ * when there is any, i.e. `{"Ok": <PassableValue>, "explain": <Explanation>}`.
+ */
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct EvaluationResult<T> {
+    #[serde(flatten)]
+    pub(crate) result: Result<T, EvaluationError>,
+    /// Set if the evaluation was made with `explain` enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) explain: Option<Explanation>,
+    /// The host calls which did not complete before their deadline.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) timed_out: Vec<TimedOutCall>,
+}
+
+impl<T> From<EvaluationError> for EvaluationResult<T> {
+    fn from(err: EvaluationError) -> Self {
+        EvaluationResult {
+            result: Err(err),
+            explain: None,
+            timed_out: vec![],
+        }
+    }
+}
+
+impl<T> EvaluationResult<T> {
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> EvaluationResult<U> {
+        EvaluationResult {
+            result: self.result.map(f),
+            explain: self.explain,
+            timed_out: self.timed_out,
+        }
+    }
+}
+
+/**
+ * Describes how an expression was evaluated, to find out which part of it decided the result.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct Explanation {
+    /// The evaluated expression, missing if the evaluation could not start.
+    pub(crate) trace: Option<TraceNode>,
+    /// Every call made to the host context, in the order they were made.
+    pub(crate) host_calls: Vec<HostCall>,
+}
+
+/**
+ * A node of the evaluated expression, mirroring the `JSONExpression` it was evaluated from.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct TraceNode {
+    /// Type of the `JSONExpression` node, i.e. `And` or `FunctionCall`.
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    /// CEL source of the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expression: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<PassableValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<EvaluationError>,
+    /// The node was not evaluated because a `||`, a ternary or `maybe` short-circuited it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) skipped: bool,
+    /// The sub-expressions, in the order they appear in the expression. For macros such as `map`,
+    /// the target is followed by the body once for each evaluated item.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) children: Vec<TraceNode>,
+}
+
+/**
+ * What the host context returns instead of a `PassableValue` when it fails to resolve a property,
+ * i.e. `{"error": "Not connected"}`.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct HostFailure {
+    pub(crate) error: String,
+}
+
+/**
+ * A call made to the host context, with the JSON of the value it returned or of the failure it ended with.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct HostCall {
+    /// The object the property belongs to, i.e. `computed`, `device` or one of the `namespaces`.
+    pub(crate) object: String,
+    pub(crate) name: String,
+    pub(crate) args: Vec<PassableValue>,
+    pub(crate) result: String,
+}
+
+/**
+ * A host call which did not complete before its deadline, and was resolved to its fallback value if it has one.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct TimedOutCall {
+    /// The object the property belongs to, i.e. `computed`, `device` or one of the `namespaces`.
+    pub(crate) object: String,
+    pub(crate) name: String,
+    pub(crate) args: Vec<PassableValue>,
+    /// Whether the property was resolved to its fallback value instead of failing.
+    pub(crate) fallback: bool,
+}
+
+/**
+ * Options shared by all the execution contexts, describing how the expression is evaluated.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct EvaluationOptions {
+    /// Host properties which must be resolved on every call instead of being memoized for the evaluation,
+    /// named by their object and name, i.e. `computed.randomNumber`.
+    pub(crate) volatile: Option<Vec<String>>,
+    /// Host properties which are read as attributes instead of being called, named by their object and name,
+    /// i.e. `device.osVersion`. They are resolved without arguments on their first access, then memoized like calls.
+    pub(crate) lazy: Option<Vec<String>>,
+    /// Limits of the resources the evaluation can use, unlimited if not set.
+    pub(crate) limits: Option<EvaluationLimits>,
+    /// Optional function libraries available to the expression.
+    pub(crate) extensions: Option<Vec<Extension>>,
+    /// The time returned by `now()`, in the same format as timestamp values.
+    /// If not set, the system time at the start of the evaluation is used, except on WebAssembly where `now()` fails.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "optional_timestamp_format")]
+    pub(crate) now: Option<DateTime<FixedOffset>>,
+    /// How long the evaluation waits for the host, unlimited if not set.
+    pub(crate) timeouts: Option<HostTimeouts>,
+    /// Values of the host properties which time out, named by their object and name, i.e. `computed.daysSinceEvent`.
+    /// Properties without a fallback fail with a `host_error` when they time out, which `maybe` can catch.
+    pub(crate) fallbacks: Option<HashMap<String, PassableValue>>,
+}
+
+/**
+ * Function libraries which are only available to the expressions enabling them, i.e. `"extensions": ["strings"]`.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Extension {
+    /// `lowerAscii`, `upperAscii`, `trim`, `split`, `join`, `replace`, `substring`, `indexOf`, `charAt` and `format`.
+    Strings,
+}
+
+/**
+ * Timeouts of the calls to the host context, in milliseconds.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct HostTimeouts {
+    /// Maximum time to wait for each host call.
+    pub(crate) host_call_millis: Option<u64>,
+    /// Maximum time to wait for all the host calls of the evaluation, counted from its start,
+    /// including the prefetched calls and, for `evaluate_rules`, the calls of every rule.
+    pub(crate) evaluation_millis: Option<u64>,
+}
+
+/**
+ * Limits protecting the host from expressions which would take too long or use too much memory to evaluate.
+ * Every limit is optional and exceeding any of them fails the evaluation with a `limit_exceeded` error.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct EvaluationLimits {
+    /// Maximum number of evaluated expression nodes, including every iteration of macros such as `map`.
+    pub(crate) max_steps: Option<u64>,
+    /// Maximum nesting depth of the expression tree.
+    pub(crate) max_depth: Option<usize>,
+    /// Maximum length in bytes of any string or bytes value produced during the evaluation.
+    pub(crate) max_string_length: Option<usize>,
+    /// Maximum number of items of any list or map produced during the evaluation.
+    pub(crate) max_list_size: Option<usize>,
+    /// Maximum number of calls to the `HostContext`, including the ones made while prefetching.
+    pub(crate) max_host_calls: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -39,8 +315,14 @@ pub enum PassableValue {
     Bytes(Vec<u8>),
     #[serde(rename = "bool")]
     Bool(bool),
-    #[serde(rename = "timestamp")]
-    Timestamp(i64),
+    /// A point in time. Serialized as an RFC 3339 string; when deserializing,
+    /// an integer is also accepted and read as seconds since the Unix epoch.
+    #[serde(rename = "timestamp", with = "timestamp_format")]
+    Timestamp(DateTime<FixedOffset>),
+    /// A span of time. Serialized as seconds with an `s` suffix, i.e. `"259200s"` or `"1.5s"`;
+    /// when deserializing, any CEL duration string such as `"72h"` or an integer number of seconds is accepted.
+    #[serde(rename = "duration", with = "duration_format")]
+    Duration(chrono::Duration),
     Null,
 }
 
@@ -60,6 +342,7 @@ impl PartialEq for PassableValue {
             (PassableValue::Bool(a), PassableValue::Bool(b)) => a == b,
             (PassableValue::Null, PassableValue::Null) => true,
             (PassableValue::Timestamp(a), PassableValue::Timestamp(b)) => a == b,
+            (PassableValue::Duration(a), PassableValue::Duration(b)) => a == b,
             // Allow different numeric types to be compared without explicit casting.
             (PassableValue::Int(a), PassableValue::UInt(b)) => a
                 .to_owned()
@@ -106,7 +389,8 @@ impl PassableValue {
             PassableValue::String(s) => Value::String(Arc::from(s.clone())),
             PassableValue::Bytes(b) => Value::Bytes(Arc::from(b.clone())),
             PassableValue::Bool(b) => Value::Bool(*b),
-            PassableValue::Timestamp(t) => Value::Int(*t),
+            PassableValue::Timestamp(t) => Value::Timestamp(*t),
+            PassableValue::Duration(d) => Value::Duration(*d),
             PassableValue::Null => Value::Null,
         }
     }
@@ -157,9 +441,113 @@ impl DisplayableValue {
             Value::String(s) => PassableValue::String((**s).clone()),
             Value::Bytes(b) => PassableValue::Bytes((**b).clone()),
             Value::Bool(b) => PassableValue::Bool(*b),
-            Value::Duration(_) => PassableValue::Null,
-            Value::Timestamp(t) => PassableValue::Timestamp(t.timestamp()),
+            Value::Duration(d) => PassableValue::Duration(*d),
+            Value::Timestamp(t) => PassableValue::Timestamp(*t),
             Value::Null => PassableValue::Null,
         }
     }
 }
+
+mod timestamp_format {
+    use chrono::{DateTime, FixedOffset, SecondsFormat, TimeZone, Utc};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TimestampRepr {
+        Seconds(i64),
+        Rfc3339(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        value: &DateTime<FixedOffset>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<FixedOffset>, D::Error> {
+        match TimestampRepr::deserialize(deserializer)? {
+            TimestampRepr::Seconds(seconds) => Utc
+                .timestamp_opt(seconds, 0)
+                .single()
+                .map(|it| it.fixed_offset())
+                .ok_or_else(|| D::Error::custom(format!("Timestamp out of range: {}", seconds))),
+            TimestampRepr::Rfc3339(text) => {
+                DateTime::parse_from_rfc3339(text.as_str()).map_err(D::Error::custom)
+            }
+        }
+    }
+}
+
+mod optional_timestamp_format {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Timestamp(#[serde(with = "super::timestamp_format")] DateTime<FixedOffset>);
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<DateTime<FixedOffset>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.map(Timestamp).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+        Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|it| it.0))
+    }
+}
+
+mod duration_format {
+    use cel_interpreter::functions::duration;
+    use cel_interpreter::Value;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::sync::Arc;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DurationRepr {
+        Seconds(i64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        value: &chrono::Duration,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sign = if *value < chrono::Duration::zero() { "-" } else { "" };
+        let value = value.abs();
+        let seconds = value.num_seconds();
+        let nanos = (value - chrono::Duration::seconds(seconds))
+            .num_nanoseconds()
+            .unwrap_or(0);
+        let formatted = if nanos == 0 {
+            format!("{}{}s", sign, seconds)
+        } else {
+            let fraction = format!("{:09}", nanos);
+            format!("{}{}.{}s", sign, seconds, fraction.trim_end_matches('0'))
+        };
+        serializer.serialize_str(&formatted)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<chrono::Duration, D::Error> {
+        match DurationRepr::deserialize(deserializer)? {
+            DurationRepr::Seconds(seconds) => chrono::Duration::try_seconds(seconds)
+                .ok_or_else(|| D::Error::custom(format!("Duration out of range: {} seconds", seconds))),
+            DurationRepr::Text(text) => match duration(Arc::new(text)) {
+                Ok(Value::Duration(d)) => Ok(d),
+                Ok(other) => Err(D::Error::custom(format!("Invalid duration: {:?}", other))),
+                Err(err) => Err(D::Error::custom(err)),
+            },
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::error::EvaluationError;
+use crate::models::{EvaluationLimits, EvaluationOptions, HostTimeouts, PassableMap, PassableValue};
+use crate::{host_failure, PropertyHost};
+use async_trait::async_trait;
+use chrono::{Duration, FixedOffset, TimeZone, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/**
+ * A single node of a `ValueTree`. Lists and maps reference their items by their index in the tree's nodes,
+ * so values can be passed through UniFFI without JSON, which does not support recursive types.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub enum ValueNode {
+    IntValue { value: i64 },
+    UIntValue { value: u64 },
+    FloatValue { value: f64 },
+    StringValue { value: String },
+    BytesValue { value: Vec<u8> },
+    BoolValue { value: bool },
+    NullValue,
+    /// A point in time, as seconds and nanoseconds since the Unix epoch and the offset from UTC it is expressed in.
+    TimestampValue { seconds: i64, nanos: u32, offset_seconds: i32 },
+    /// A span of time, as seconds and the nanoseconds on top of them, which have the same sign.
+    DurationValue { seconds: i64, nanos: i32 },
+    ListValue { items: Vec<u32> },
+    MapValue { entries: Vec<MapEntry> },
+    /// A host property, as declared in `computed` or `device`, with the list of its arguments if it has any.
+    FunctionValue { name: String, args: Option<u32> },
+}
+
+/**
+ * An entry of a `ValueNode::MapValue`, referencing its value by its index in the tree's nodes.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct MapEntry {
+    pub key: String,
+    pub value: u32,
+}
+
+/**
+ * A value flattened into a list of nodes. Nodes can only reference nodes before them, so trees are built bottom-up,
+ * i.e. `[1, 2]` is `[IntValue(1), IntValue(2), ListValue([0, 1])]` with the root at index `2`.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct ValueTree {
+    pub nodes: Vec<ValueNode>,
+    pub root: u32,
+}
+
+/**
+ * The typed counterpart of the JSON `ExecutionContext`, without the expression.
+ * The `variables` tree must have a map as root, and each of the `computed`, `device` and `namespaces` trees
+ * a list of arguments.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct NativeExecutionContext {
+    pub variables: ValueTree,
+    pub computed: HashMap<String, ValueTree>,
+    pub device: HashMap<String, ValueTree>,
+    pub namespaces: Option<HashMap<String, HashMap<String, ValueTree>>>,
+    pub options: NativeOptions,
+}
+
+/**
+ * The typed counterpart of the options of the JSON execution contexts.
+ */
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct NativeOptions {
+    pub volatile: Vec<String>,
+    pub lazy: Vec<String>,
+    pub limits: Option<NativeLimits>,
+    pub extensions: Vec<String>,
+    /// The time returned by `now()`, in milliseconds since the Unix epoch.
+    pub now_millis: Option<i64>,
+    pub timeouts: Option<NativeTimeouts>,
+    /// Values of the host properties which time out, named by their object and name.
+    pub fallbacks: Option<HashMap<String, ValueTree>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct NativeTimeouts {
+    pub host_call_millis: Option<u64>,
+    pub evaluation_millis: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct NativeLimits {
+    pub max_steps: Option<u64>,
+    pub max_depth: Option<u32>,
+    pub max_string_length: Option<u64>,
+    pub max_list_size: Option<u64>,
+    pub max_host_calls: Option<u64>,
+}
+
+/**
+ * The typed counterpart of `EvaluationError`, thrown by the native API.
+ * Messages are named `reason` since `message` is already a member of Kotlin exceptions.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub enum NativeError {
+    Parse { reason: String, start: Option<u64>, end: Option<u64> },
+    /// The values passed in by the host do not match the expected model, i.e. a node references a missing node.
+    Deserialization { reason: String },
+    UndeclaredReference { reason: String, identifier: String },
+    FunctionError { reason: String, function: Option<String> },
+    HostError { reason: String, property: String },
+    TypeMismatch { reason: String },
+    LimitExceeded { reason: String, limit: String },
+    Unsupported { reason: String },
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            NativeError::Parse { reason, .. }
+            | NativeError::Deserialization { reason }
+            | NativeError::UndeclaredReference { reason, .. }
+            | NativeError::FunctionError { reason, .. }
+            | NativeError::HostError { reason, .. }
+            | NativeError::TypeMismatch { reason }
+            | NativeError::LimitExceeded { reason, .. }
+            | NativeError::Unsupported { reason } => reason,
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for NativeError {}
+
+/// Thrown when a `NativeHostContext` implementation throws an error that is not a `NativeError`.
+impl From<uniffi::UnexpectedUniFFICallbackError> for NativeError {
+    fn from(err: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        NativeError::HostError { reason: err.reason, property: String::new() }
+    }
+}
+
+impl From<EvaluationError> for NativeError {
+    fn from(err: EvaluationError) -> Self {
+        match err {
+            EvaluationError::Parse { message, span, .. } => NativeError::Parse {
+                reason: message,
+                start: span.as_ref().map(|it| it.start as u64),
+                end: span.as_ref().map(|it| it.end as u64),
+            },
+            EvaluationError::Deserialization { message } => NativeError::Deserialization { reason: message },
+            EvaluationError::UndeclaredReference { message, identifier } => NativeError::UndeclaredReference {
+                reason: message,
+                identifier,
+            },
+            EvaluationError::FunctionError { message, function } => NativeError::FunctionError {
+                reason: message,
+                function,
+            },
+            EvaluationError::HostError { message, property } => NativeError::HostError {
+                reason: message,
+                property,
+            },
+            EvaluationError::TypeMismatch { message } => NativeError::TypeMismatch { reason: message },
+            EvaluationError::LimitExceeded { message, limit } => NativeError::LimitExceeded { reason: message, limit },
+            EvaluationError::Unsupported { message } => NativeError::Unsupported { reason: message },
+        }
+    }
+}
+
+impl From<&PassableValue> for ValueTree {
+    fn from(value: &PassableValue) -> Self {
+        let mut nodes = vec![];
+        let root = push_node(&mut nodes, value);
+        ValueTree { nodes, root }
+    }
+}
+
+/**
+ * Appends the nodes of a value after the nodes of its children, returning the index of its node.
+ */
+fn push_node(nodes: &mut Vec<ValueNode>, value: &PassableValue) -> u32 {
+    let node = match value {
+        PassableValue::Int(value) => ValueNode::IntValue { value: *value },
+        PassableValue::UInt(value) => ValueNode::UIntValue { value: *value },
+        PassableValue::Float(value) => ValueNode::FloatValue { value: *value },
+        PassableValue::String(value) => ValueNode::StringValue { value: value.clone() },
+        PassableValue::Bytes(value) => ValueNode::BytesValue { value: value.clone() },
+        PassableValue::Bool(value) => ValueNode::BoolValue { value: *value },
+        PassableValue::Null => ValueNode::NullValue,
+        PassableValue::Timestamp(value) => ValueNode::TimestampValue {
+            seconds: value.timestamp(),
+            nanos: value.timestamp_subsec_nanos(),
+            offset_seconds: value.offset().local_minus_utc(),
+        },
+        PassableValue::Duration(value) => ValueNode::DurationValue {
+            seconds: value.num_seconds(),
+            nanos: value.subsec_nanos(),
+        },
+        PassableValue::List(items) => ValueNode::ListValue {
+            items: items.iter().map(|it| push_node(nodes, it)).collect(),
+        },
+        PassableValue::PMap(map) => ValueNode::MapValue {
+            entries: map
+                .iter()
+                .map(|(key, value)| MapEntry {
+                    key: key.clone(),
+                    value: push_node(nodes, value),
+                })
+                .collect(),
+        },
+        PassableValue::Function(name, args) => ValueNode::FunctionValue {
+            name: name.clone(),
+            args: args.as_ref().map(|it| push_node(nodes, it)),
+        },
+    };
+    nodes.push(node);
+    (nodes.len() - 1) as u32
+}
+
+impl TryFrom<&ValueTree> for PassableValue {
+    type Error = EvaluationError;
+
+    fn try_from(tree: &ValueTree) -> Result<Self, Self::Error> {
+        read_node(&tree.nodes, tree.root, tree.nodes.len())
+    }
+}
+
+/**
+ * Reads the value of the node at the given index, which must be before the given bound so that references
+ * always go backwards and a tree cannot contain cycles.
+ */
+fn read_node(nodes: &[ValueNode], index: u32, bound: usize) -> Result<PassableValue, EvaluationError> {
+    let invalid = |message: String| EvaluationError::Deserialization { message };
+    let index = index as usize;
+    if index >= bound {
+        return Err(invalid(format!(
+            "Node {} is not before the node referencing it among {} nodes",
+            index,
+            nodes.len()
+        )));
+    }
+    Ok(match &nodes[index] {
+        ValueNode::IntValue { value } => PassableValue::Int(*value),
+        ValueNode::UIntValue { value } => PassableValue::UInt(*value),
+        ValueNode::FloatValue { value } => PassableValue::Float(*value),
+        ValueNode::StringValue { value } => PassableValue::String(value.clone()),
+        ValueNode::BytesValue { value } => PassableValue::Bytes(value.clone()),
+        ValueNode::BoolValue { value } => PassableValue::Bool(*value),
+        ValueNode::NullValue => PassableValue::Null,
+        ValueNode::TimestampValue {
+            seconds,
+            nanos,
+            offset_seconds,
+        } => {
+            let offset = FixedOffset::east_opt(*offset_seconds)
+                .ok_or_else(|| invalid(format!("Invalid offset: {} seconds", offset_seconds)))?;
+            let timestamp = Utc
+                .timestamp_opt(*seconds, *nanos)
+                .single()
+                .ok_or_else(|| invalid(format!("Timestamp out of range: {}.{} seconds", seconds, nanos)))?;
+            PassableValue::Timestamp(timestamp.with_timezone(&offset))
+        }
+        ValueNode::DurationValue { seconds, nanos } => {
+            let duration = Duration::try_seconds(*seconds)
+                .and_then(|it| it.checked_add(&Duration::nanoseconds(*nanos as i64)))
+                .ok_or_else(|| invalid(format!("Duration out of range: {}.{} seconds", seconds, nanos)))?;
+            PassableValue::Duration(duration)
+        }
+        ValueNode::ListValue { items } => PassableValue::List(
+            items
+                .iter()
+                .map(|it| read_node(nodes, *it, index))
+                .collect::<Result<_, _>>()?,
+        ),
+        ValueNode::MapValue { entries } => PassableValue::PMap(
+            entries
+                .iter()
+                .map(|it| Ok((it.key.clone(), read_node(nodes, it.value, index)?)))
+                .collect::<Result<_, EvaluationError>>()?,
+        ),
+        ValueNode::FunctionValue { name, args } => PassableValue::Function(
+            name.clone(),
+            args.map(|it| read_node(nodes, it, index).map(Box::new)).transpose()?,
+        ),
+    })
+}
+
+/**
+ * The parts of a `NativeExecutionContext` in the form the evaluation uses.
+ */
+pub(crate) struct Bindings {
+    pub(crate) variables: PassableMap,
+    pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) namespaces: Option<HashMap<String, HashMap<String, Vec<PassableValue>>>>,
+    pub(crate) options: EvaluationOptions,
+}
+
+impl TryFrom<NativeExecutionContext> for Bindings {
+    type Error = EvaluationError;
+
+    fn try_from(context: NativeExecutionContext) -> Result<Self, Self::Error> {
+        let invalid = |message: String| EvaluationError::Deserialization { message };
+        let variables = match PassableValue::try_from(&context.variables)? {
+            PassableValue::PMap(map) => PassableMap { map },
+            other => return Err(invalid(format!("Expected a map of variables, got {:?}", other))),
+        };
+        let properties = |declared: HashMap<String, ValueTree>| -> Result<HashMap<_, _>, EvaluationError> {
+            declared
+                .into_iter()
+                .map(|(name, args)| match PassableValue::try_from(&args)? {
+                    PassableValue::List(args) => Ok((name, args)),
+                    other => Err(invalid(format!("Expected a list of arguments for {}, got {:?}", name, other))),
+                })
+                .collect::<Result<HashMap<_, _>, _>>()
+        };
+        let options = context.options;
+        let extensions = options
+            .extensions
+            .iter()
+            .map(|it| serde_json::from_value(serde_json::Value::String(it.clone())))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(EvaluationError::deserialization)?;
+        let fallbacks = options
+            .fallbacks
+            .iter()
+            .flatten()
+            .map(|(property, value)| Ok((property.clone(), PassableValue::try_from(value)?)))
+            .collect::<Result<HashMap<_, _>, EvaluationError>>()?;
+        let now = match options.now_millis {
+            Some(millis) => Some(
+                Utc.timestamp_millis_opt(millis)
+                    .single()
+                    .map(|it| it.fixed_offset())
+                    .ok_or_else(|| invalid(format!("Timestamp out of range: {} milliseconds", millis)))?,
+            ),
+            None => None,
+        };
+        Ok(Bindings {
+            variables,
+            computed: Some(properties(context.computed)?),
+            device: Some(properties(context.device)?),
+            namespaces: context
+                .namespaces
+                .map(|namespaces| {
+                    namespaces
+                        .into_iter()
+                        .map(|(object, declared)| Ok((object, properties(declared)?)))
+                        .collect::<Result<HashMap<_, _>, EvaluationError>>()
+                })
+                .transpose()?,
+            options: EvaluationOptions {
+                volatile: Some(options.volatile),
+                lazy: Some(options.lazy),
+                limits: options.limits.map(|it| EvaluationLimits {
+                    max_steps: it.max_steps,
+                    max_depth: it.max_depth.map(|it| it as usize),
+                    max_string_length: it.max_string_length.map(|it| it as usize),
+                    max_list_size: it.max_list_size.map(|it| it as usize),
+                    max_host_calls: it.max_host_calls.map(|it| it as usize),
+                }),
+                extensions: Some(extensions),
+                now,
+                timeouts: options.timeouts.map(|it| HostTimeouts {
+                    host_call_millis: it.host_call_millis,
+                    evaluation_millis: it.evaluation_millis,
+                }),
+                fallbacks: Some(fallbacks),
+            },
+        })
+    }
+}
+
+/**
+ * Host context trait of the native API, resolving properties from and to typed values instead of JSON.
+ * The arguments are passed as a tree whose root is the list of arguments.
+ * A thrown error fails the evaluation with a `HostError` naming the property, unless it is caught with `maybe`.
+ */
+#[async_trait]
+pub trait NativeHostContext: Send + Sync {
+    async fn computed_property(&self, name: String, args: ValueTree) -> Result<ValueTree, NativeError>;
+
+    async fn device_property(&self, name: String, args: ValueTree) -> Result<ValueTree, NativeError>;
+
+    /**
+     * Resolves a property of any host object declared in the `namespaces` of the context.
+     * By default, `computed` and `device` properties are resolved by the methods above,
+     * and the properties of other objects fail.
+     */
+    async fn property(&self, namespace: String, name: String, args: ValueTree) -> Result<ValueTree, NativeError> {
+        match namespace.as_str() {
+            "computed" => self.computed_property(name, args).await,
+            "device" => self.device_property(name, args).await,
+            _ => Err(NativeError::HostError {
+                reason: format!("No host object named {}", namespace),
+                property: format!("{}.{}", namespace, name),
+            }),
+        }
+    }
+}
+
+/**
+ * Adapts a `NativeHostContext` to the `PropertyHost` the evaluation uses, so that host calls are memoized,
+ * prefetched and limited exactly like for the JSON API. Values are converted between trees and `PassableValue`s
+ * directly, without going through JSON.
+ */
+pub(crate) struct NativeHost(pub(crate) Arc<dyn NativeHostContext>);
+
+#[async_trait]
+impl PropertyHost for NativeHost {
+    /**
+     * Calls the host with the arguments as a tree whose root is their list. The error it throws, or the tree it returns
+     * being invalid, fails the evaluation just like an error returned by a JSON host does.
+     */
+    async fn property(&self, object: &str, name: &str, args: &[PassableValue]) -> Result<PassableValue, EvaluationError> {
+        let args = ValueTree::from(&PassableValue::List(args.to_vec()));
+        self.0
+            .property(object.to_string(), name.to_string(), args)
+            .await
+            .and_then(|it| PassableValue::try_from(&it).map_err(NativeError::from))
+            .map_err(|err| host_failure(format!("{}.{}", object, name), err))
+    }
+}
@@ -0,0 +1,216 @@
+use crate::analysis::{is_builtin, walk, walk_children, MACROS};
+use crate::eval::to_bool;
+use crate::partial::{as_bool, value_to_expression};
+use cel_interpreter::{Context, Expression, Value};
+use cel_parser::{ArithmeticOp, Atom, Member, UnaryOp};
+
+/**
+ * Simplifies an expression without changing what it evaluates to, including the errors it fails with:
+ * constant sub-expressions are replaced by their value, boolean identities such as `true && x` are simplified
+ * and double negations are removed.
+ * @param expression The expression to optimize
+ * @return The optimized expression
+ */
+pub(crate) fn optimize(expression: &Expression) -> Expression {
+    let mut ctx = Context::default();
+    crate::add_library_functions(&mut ctx);
+    Optimizer { ctx }.optimize(expression)
+}
+
+struct Optimizer {
+    // Contains only the functions that return the same value for the same arguments
+    ctx: Context<'static>,
+}
+
+impl Optimizer {
+    fn optimize(&self, expression: &Expression) -> Expression {
+        if is_pure(expression, &mut vec![]) && !matches!(expression, Expression::Atom(_)) && !may_panic(expression) {
+            // Constants failing to evaluate are kept, so they fail the same way when the expression is evaluated
+            if let Some(folded) = self.ctx.resolve(expression).ok().as_ref().and_then(value_to_expression) {
+                return folded;
+            }
+        }
+        let optimized = match expression {
+            Expression::Ternary(condition, if_true, if_false) => Expression::Ternary(
+                Box::new(self.optimize(condition)),
+                Box::new(self.optimize(if_true)),
+                Box::new(self.optimize(if_false)),
+            ),
+            Expression::Or(left, right) => {
+                Expression::Or(Box::new(self.optimize(left)), Box::new(self.optimize(right)))
+            }
+            Expression::And(left, right) => {
+                Expression::And(Box::new(self.optimize(left)), Box::new(self.optimize(right)))
+            }
+            Expression::Relation(left, op, right) => Expression::Relation(
+                Box::new(self.optimize(left)),
+                op.clone(),
+                Box::new(self.optimize(right)),
+            ),
+            Expression::Arithmetic(left, op, right) => Expression::Arithmetic(
+                Box::new(self.optimize(left)),
+                op.clone(),
+                Box::new(self.optimize(right)),
+            ),
+            Expression::Unary(op, operand) => Expression::Unary(op.clone(), Box::new(self.optimize(operand))),
+            Expression::Member(target, member) => {
+                let member = match member.as_ref() {
+                    Member::Attribute(_) => member.as_ref().clone(),
+                    Member::Index(index) => Member::Index(Box::new(self.optimize(index))),
+                    Member::Fields(fields) => Member::Fields(
+                        fields
+                            .iter()
+                            .map(|(name, value)| (name.clone(), self.optimize(value)))
+                            .collect(),
+                    ),
+                };
+                Expression::Member(Box::new(self.optimize(target)), Box::new(member))
+            }
+            Expression::FunctionCall(function, target, args) => Expression::FunctionCall(
+                function.clone(),
+                target.as_ref().map(|it| Box::new(self.optimize(it))),
+                args.iter().map(|it| self.optimize(it)).collect(),
+            ),
+            Expression::List(items) => Expression::List(items.iter().map(|it| self.optimize(it)).collect()),
+            Expression::Map(entries) => Expression::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| (self.optimize(key), self.optimize(value)))
+                    .collect(),
+            ),
+            Expression::Atom(_) | Expression::Ident(_) => expression.clone(),
+        };
+        simplify(optimized)
+    }
+}
+
+/**
+ * Applies the identities of the logical operators to an expression whose children are already optimized.
+ */
+fn simplify(expression: Expression) -> Expression {
+    match expression {
+        // `||` returns its left side if it is truthy, its right side otherwise
+        Expression::Or(left, right) => match literal_truthiness(&left) {
+            Some(true) => *left,
+            Some(false) => *right,
+            None if matches!(literal_truthiness(&right), Some(false)) && is_bool(&left) => *left,
+            None => Expression::Or(left, right),
+        },
+        // `&&` evaluates both of its sides, so only a truthy side can be dropped without dropping an error
+        Expression::And(left, right) => match (literal_truthiness(&left), literal_truthiness(&right)) {
+            (Some(true), _) => as_bool(*right),
+            (_, Some(true)) => as_bool(*left),
+            _ => Expression::And(left, right),
+        },
+        Expression::Ternary(condition, if_true, if_false) => match literal_truthiness(&condition) {
+            Some(true) => *if_true,
+            Some(false) => *if_false,
+            None => match (literal_truthiness(&if_true), literal_truthiness(&if_false)) {
+                (Some(true), Some(false)) if is_bool(&if_true) && is_bool(&if_false) => {
+                    simplify(Expression::Unary(UnaryOp::DoubleNot, condition))
+                }
+                (Some(false), Some(true)) if is_bool(&if_true) && is_bool(&if_false) => {
+                    simplify(Expression::Unary(UnaryOp::Not, condition))
+                }
+                _ => Expression::Ternary(condition, if_true, if_false),
+            },
+        },
+        Expression::Unary(op, operand) => match (op, *operand) {
+            // `!!x` converts `x` to a boolean, which does nothing if it already is one
+            (UnaryOp::DoubleNot, operand) if is_bool(&operand) => operand,
+            (UnaryOp::Not, Expression::Unary(UnaryOp::Not, operand)) => {
+                simplify(Expression::Unary(UnaryOp::DoubleNot, operand))
+            }
+            (UnaryOp::Not, Expression::Unary(UnaryOp::DoubleNot, operand)) => Expression::Unary(UnaryOp::Not, operand),
+            (op, operand) => Expression::Unary(op, Box::new(operand)),
+        },
+        expression => expression,
+    }
+}
+
+/**
+ * The truthiness of the expression if it is a literal.
+ */
+fn literal_truthiness(expression: &Expression) -> Option<bool> {
+    match expression {
+        Expression::Atom(_) | Expression::List(_) | Expression::Map(_)
+            if is_pure(expression, &mut vec![]) && !may_panic(expression) =>
+        {
+            Context::default().resolve(expression).ok().map(|it| to_bool(&it))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Checks if the expression always evaluates to a boolean when it does not fail.
+ */
+fn is_bool(expression: &Expression) -> bool {
+    matches!(
+        expression,
+        Expression::Atom(Atom::Bool(_))
+            | Expression::Relation(..)
+            | Expression::And(..)
+            | Expression::Unary(UnaryOp::Not | UnaryOp::DoubleNot, _)
+    )
+}
+
+/**
+ * Checks if the expression evaluates to the same value in any context, referencing only the variables bound
+ * by the macros it contains and calling only built in functions.
+ */
+fn is_pure(expression: &Expression, bound: &mut Vec<String>) -> bool {
+    match expression {
+        Expression::Ident(name) => bound.iter().any(|it| it == name.as_str()),
+        Expression::FunctionCall(function, target, args) => {
+            let name = match function.as_ref() {
+                // `now()` returns a different value on every evaluation
+                Expression::Ident(name) if is_builtin(name) && name.as_str() != "now" => {
+                    name.as_str()
+                }
+                _ => return false,
+            };
+            if !target.as_ref().map(|it| is_pure(it, bound)).unwrap_or(true) {
+                return false;
+            }
+            match args.as_slice() {
+                [Expression::Ident(variable), body] if target.is_some() && MACROS.contains(&name) => {
+                    bound.push(variable.to_string());
+                    let pure = is_pure(body, bound);
+                    bound.pop();
+                    pure
+                }
+                _ => args.iter().all(|it| is_pure(it, bound)),
+            }
+        }
+        _ => {
+            let mut pure = true;
+            walk_children(expression, &mut |child| pure = pure && is_pure(child, bound));
+            pure
+        }
+    }
+}
+
+/**
+ * The interpreter panics on integer divisions by zero and on the overflow of `i64::MIN / -1` instead of failing,
+ * so constants are only evaluated if all their divisors are known not to be zero, and not to be `-1`
+ * unless the dividend is known not to be `i64::MIN`.
+ */
+fn may_panic(expression: &Expression) -> bool {
+    let mut may_panic = false;
+    walk(expression, &mut |it| {
+        if let Expression::Arithmetic(dividend, ArithmeticOp::Divide | ArithmeticOp::Modulus, divisor) = it {
+            let resolve = |it: &Expression| Context::default().resolve(it);
+            may_panic = may_panic
+                || self::may_panic(divisor)
+                || match resolve(divisor) {
+                    Ok(Value::Float(_)) | Ok(Value::Int(1..)) | Ok(Value::Int(..=-2)) | Ok(Value::UInt(1..)) => false,
+                    Ok(Value::Int(-1)) => {
+                        self::may_panic(dividend) || !matches!(resolve(dividend), Ok(Value::Int(dividend)) if dividend != i64::MIN)
+                    }
+                    _ => true,
+                };
+        }
+    });
+    may_panic
+}
@@ -0,0 +1,391 @@
+use crate::analysis::{HOST_OBJECTS, MACROS};
+use crate::error::EvaluationError;
+use crate::eval::{self, to_bool, Budget};
+use crate::models::{PartialResult, PassableValue};
+use crate::DisplayableValue;
+use cel_interpreter::objects::Key;
+use cel_interpreter::{Context, Expression, Value};
+use cel_parser::{Atom, Member, UnaryOp};
+use std::sync::Arc;
+
+/**
+ * Result of partially evaluating an expression.
+ */
+enum Partial {
+    /// The value does not depend on any unknown.
+    Known(Value),
+    /// The remaining expression, referencing only the unknowns and the values which could not be simplified.
+    Residual(Expression),
+    /// Evaluating the expression failed, which only fails the evaluation if the expression is not skipped.
+    Failed(EvaluationError),
+}
+
+/**
+ * Evaluates everything in the expression that does not depend on the unknowns, simplifying it into
+ * either a value or a residual expression which references only the unknowns.
+ * @param expression The expression to evaluate
+ * @param ctx The context to evaluate the known parts of the expression in
+ * @param budget The budget the evaluation is limited by
+ * @param unknowns The unknown identifiers, member paths or host properties, i.e. `user`, `user.lastPurchase` or `computed.daysSince`
+ */
+pub(crate) fn partial_evaluate(
+    expression: &Expression,
+    ctx: &Context,
+    budget: &Budget,
+    unknowns: &[String],
+) -> Result<PartialResult, EvaluationError> {
+    let evaluator = PartialEvaluator {
+        ctx,
+        budget,
+        unknowns,
+        skippable: false,
+    };
+    match evaluator.partial(expression)? {
+        Partial::Known(value) => Ok(PartialResult::Value(DisplayableValue(value).to_passable())),
+        Partial::Residual(residual) => Ok(PartialResult::Residual(residual.into())),
+        Partial::Failed(err) => Err(err),
+    }
+}
+
+struct PartialEvaluator<'a, 'c> {
+    ctx: &'a Context<'c>,
+    budget: &'a Budget,
+    unknowns: &'a [String],
+    // Whether the expression may be skipped by the evaluation, in which case the host is not called for it
+    skippable: bool,
+}
+
+impl PartialEvaluator<'_, '_> {
+    fn partial(&self, expression: &Expression) -> Result<Partial, EvaluationError> {
+        let deferred = self.references_unknown(expression, &mut vec![]) || (self.skippable && self.calls_host(expression));
+        if !deferred {
+            return match eval::evaluate(expression, self.ctx, self.budget, None) {
+                Ok(value) => Ok(Partial::Known(value)),
+                Err(err @ EvaluationError::LimitExceeded { .. }) => Err(err),
+                Err(err) => Ok(Partial::Failed(err)),
+            };
+        }
+        let partial = match expression {
+            Expression::Or(left, right) => match self.partial(left)? {
+                Partial::Known(value) if to_bool(&value) => Partial::Known(value),
+                Partial::Known(_) => self.partial(right)?,
+                Partial::Residual(left) => Partial::Residual(Expression::Or(
+                    Box::new(left),
+                    Box::new(self.residual_or_original(right)?),
+                )),
+                failed => failed,
+            },
+            // Both sides of `&&` are evaluated, so a residual is kept next to `false` for the errors it may raise
+            Expression::And(left, right) => match (self.partial(left)?, self.partial(right)?) {
+                (Partial::Failed(err), _) | (_, Partial::Failed(err)) => Partial::Failed(err),
+                (Partial::Known(left), Partial::Known(right)) => {
+                    Partial::Known(Value::Bool(to_bool(&left) && to_bool(&right)))
+                }
+                (Partial::Known(value), Partial::Residual(residual))
+                | (Partial::Residual(residual), Partial::Known(value))
+                    if to_bool(&value) =>
+                {
+                    Partial::Residual(as_bool(residual))
+                }
+                (left_partial, right_partial) => Partial::Residual(Expression::And(
+                    Box::new(self.residual(left_partial, left)),
+                    Box::new(self.residual(right_partial, right)),
+                )),
+            },
+            Expression::Ternary(condition, if_true, if_false) => match self.partial(condition)? {
+                Partial::Known(value) if to_bool(&value) => self.partial(if_true)?,
+                Partial::Known(_) => self.partial(if_false)?,
+                Partial::Residual(condition) => Partial::Residual(Expression::Ternary(
+                    Box::new(condition),
+                    Box::new(self.residual_or_original(if_true)?),
+                    Box::new(self.residual_or_original(if_false)?),
+                )),
+                failed => failed,
+            },
+            Expression::FunctionCall(function, Some(target), args)
+                if matches!(function.as_ref(), Expression::Ident(name) if MACROS.contains(&name.as_str())) =>
+            {
+                let target = match self.partial(target)? {
+                    Partial::Failed(err) => return Ok(Partial::Failed(err)),
+                    partial => self.residual(partial, target),
+                };
+                let args = match args.as_slice() {
+                    // The variable bound by the macro is only known while iterating, so the body is simplified without it
+                    [Expression::Ident(variable), body] => {
+                        let mut unknowns = self.unknowns.to_vec();
+                        unknowns.push(variable.to_string());
+                        let body = PartialEvaluator { unknowns: &unknowns, ..*self }.residual_or_original(body)?;
+                        vec![Expression::Ident(variable.clone()), body]
+                    }
+                    _ => args.clone(),
+                };
+                Partial::Residual(Expression::FunctionCall(function.clone(), Some(Box::new(target)), args))
+            }
+            Expression::FunctionCall(function, target, args) => match function.as_ref() {
+                // `has` tests for the presence of the field rather than evaluating it
+                Expression::Ident(name) if name.as_str() == "has" => Partial::Residual(expression.clone()),
+                // `maybe` only evaluates its second argument when the first one fails
+                Expression::Ident(name) if name.as_str() == "maybe" => {
+                    let target = match target {
+                        Some(target) => Some(Box::new(self.residual_or_original(target)?)),
+                        None => None,
+                    };
+                    let args = args
+                        .iter()
+                        .map(|arg| self.residual_or_original(arg))
+                        .collect::<Result<_, _>>()?;
+                    Partial::Residual(Expression::FunctionCall(function.clone(), target, args))
+                }
+                _ => {
+                    let children: Vec<&Expression> = target.iter().map(|it| it.as_ref()).chain(args).collect();
+                    self.rebuild(&children, |mut residuals| {
+                        let target = target.as_ref().map(|_| Box::new(residuals.remove(0)));
+                        Expression::FunctionCall(function.clone(), target, residuals)
+                    })?
+                }
+            },
+            Expression::Member(target, member) => match member.as_ref() {
+                Member::Index(index) => self.rebuild(&[target, index], |mut residuals| {
+                    let index = residuals.pop().unwrap();
+                    let target = residuals.pop().unwrap();
+                    Expression::Member(Box::new(target), Box::new(Member::Index(Box::new(index))))
+                })?,
+                // Attributes of an unknown value stay unknown, no matter if the value itself is declared unknown or not
+                Member::Attribute(_) if member_path(expression).is_some() => Partial::Residual(expression.clone()),
+                _ => self.rebuild(&[target], |mut residuals| {
+                    Expression::Member(Box::new(residuals.pop().unwrap()), member.clone())
+                })?,
+            },
+            Expression::Arithmetic(left, op, right) => self.rebuild(&[left, right], |mut residuals| {
+                let right = residuals.pop().unwrap();
+                let left = residuals.pop().unwrap();
+                Expression::Arithmetic(Box::new(left), op.clone(), Box::new(right))
+            })?,
+            Expression::Relation(left, op, right) => self.rebuild(&[left, right], |mut residuals| {
+                let right = residuals.pop().unwrap();
+                let left = residuals.pop().unwrap();
+                Expression::Relation(Box::new(left), op.clone(), Box::new(right))
+            })?,
+            Expression::Unary(op, operand) => self.rebuild(&[operand], |mut residuals| {
+                Expression::Unary(op.clone(), Box::new(residuals.pop().unwrap()))
+            })?,
+            Expression::List(items) => self.rebuild(&items.iter().collect::<Vec<_>>(), Expression::List)?,
+            Expression::Map(entries) => {
+                let children: Vec<&Expression> = entries.iter().flat_map(|(key, value)| [key, value]).collect();
+                self.rebuild(&children, |residuals| {
+                    Expression::Map(
+                        residuals
+                            .chunks(2)
+                            .map(|entry| (entry[0].clone(), entry[1].clone()))
+                            .collect(),
+                    )
+                })?
+            }
+            Expression::Ident(_) | Expression::Atom(_) => Partial::Residual(expression.clone()),
+        };
+        Ok(partial)
+    }
+
+    /**
+     * Partially evaluates the children of an expression which evaluates all of them, failing as soon as one of them fails,
+     * and builds the residual expression from the simplified children.
+     */
+    fn rebuild(
+        &self,
+        children: &[&Expression],
+        build: impl FnOnce(Vec<Expression>) -> Expression,
+    ) -> Result<Partial, EvaluationError> {
+        let mut residuals = Vec::with_capacity(children.len());
+        for child in children {
+            match self.partial(child)? {
+                Partial::Failed(err) => return Ok(Partial::Failed(err)),
+                partial => residuals.push(self.residual(partial, child)),
+            }
+        }
+        Ok(Partial::Residual(build(residuals)))
+    }
+
+    /**
+     * Partially evaluates an expression which might end up being skipped, keeping it as it is if it fails.
+     * Its host properties are left in the residual, so the host is only called for the ones the evaluation reaches.
+     */
+    fn residual_or_original(&self, expression: &Expression) -> Result<Expression, EvaluationError> {
+        let evaluator = PartialEvaluator {
+            skippable: true,
+            ..*self
+        };
+        Ok(self.residual(evaluator.partial(expression)?, expression))
+    }
+
+    /**
+     * Checks if evaluating the expression calls the host, either for a host property or a lazy attribute.
+     */
+    fn calls_host(&self, expression: &Expression) -> bool {
+        let mut calls = false;
+        crate::analysis::walk(expression, &mut |it| {
+            calls = calls
+                || match it {
+                    Expression::FunctionCall(function, Some(target), _) => match function.as_ref() {
+                        Expression::Ident(name) => eval::host_function(self.ctx, name, target).is_some(),
+                        _ => false,
+                    },
+                    Expression::Member(target, member) => eval::lazy_attribute(self.ctx, target, member).is_some(),
+                    _ => false,
+                };
+        });
+        calls
+    }
+
+    /**
+     * Turns a partial result back into an expression, falling back to the original expression
+     * if the value cannot be written as a literal.
+     */
+    fn residual(&self, partial: Partial, original: &Expression) -> Expression {
+        match partial {
+            Partial::Known(value) => value_to_expression(&value).unwrap_or_else(|| original.clone()),
+            Partial::Residual(residual) => residual,
+            Partial::Failed(_) => original.clone(),
+        }
+    }
+
+    /**
+     * Checks if the expression references any of the unknowns, ignoring variables bound by macros.
+     */
+    fn references_unknown(&self, expression: &Expression, bound: &mut Vec<String>) -> bool {
+        if let Some(path) = member_path(expression) {
+            return !bound.contains(&path[0]) && self.is_unknown(&path.join("."));
+        }
+        match expression {
+            Expression::FunctionCall(function, target, args) => {
+                let name = match function.as_ref() {
+                    Expression::Ident(name) => name.as_str(),
+                    _ => "",
+                };
+                let target_unknown = match target.as_deref() {
+                    // Host properties are called on their object, which is only unknown as a whole when it is declared unknown
+                    Some(Expression::Ident(object))
+                        if HOST_OBJECTS.contains(&object.as_str()) && !bound.contains(object.as_ref()) =>
+                    {
+                        self.is_unknown(&format!("{}.{}", object, name))
+                    }
+                    Some(target) => self.references_unknown(target, bound),
+                    None => false,
+                };
+                if target_unknown {
+                    return true;
+                }
+                match args.as_slice() {
+                    [Expression::Ident(variable), body] if target.is_some() && MACROS.contains(&name) => {
+                        bound.push(variable.to_string());
+                        let references = self.references_unknown(body, bound);
+                        bound.pop();
+                        references
+                    }
+                    _ => args.iter().any(|it| self.references_unknown(it, bound)),
+                }
+            }
+            _ => {
+                let mut references = false;
+                crate::analysis::walk_children(expression, &mut |child| {
+                    references = references || self.references_unknown(child, bound);
+                });
+                references
+            }
+        }
+    }
+
+    /**
+     * An unknown path makes unknown all the paths below it, and the paths above it since their value contains it.
+     */
+    fn is_unknown(&self, path: &str) -> bool {
+        self.unknowns.iter().any(|unknown| {
+            unknown == path
+                || path.starts_with(&format!("{}.", unknown))
+                || unknown.starts_with(&format!("{}.", path))
+        })
+    }
+}
+
+/**
+ * Splits a chain of attribute accesses such as `user.profile.name` into the root identifier and the attribute names.
+ */
+fn member_path(expression: &Expression) -> Option<Vec<String>> {
+    match expression {
+        Expression::Ident(name) => Some(vec![name.to_string()]),
+        Expression::Member(inner, member) => match member.as_ref() {
+            Member::Attribute(name) => member_path(inner).map(|mut path| {
+                path.push(name.to_string());
+                path
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/**
+ * `&&` always evaluates to a boolean, so when only one of its sides is left it has to be converted to one,
+ * unless it already evaluates to a boolean.
+ */
+pub(crate) fn as_bool(expression: Expression) -> Expression {
+    match expression {
+        Expression::Atom(Atom::Bool(_))
+        | Expression::Relation(..)
+        | Expression::And(..)
+        | Expression::Unary(UnaryOp::Not | UnaryOp::DoubleNot, _) => expression,
+        _ => Expression::Unary(UnaryOp::DoubleNot, Box::new(expression)),
+    }
+}
+
+/**
+ * Writes a value as an expression evaluating to it, if it can be written as one.
+ */
+pub(crate) fn value_to_expression(value: &Value) -> Option<Expression> {
+    let atom = match value {
+        Value::Int(it) => Atom::Int(*it),
+        Value::UInt(it) => Atom::UInt(*it),
+        // Non-finite floats cannot be written in JSON
+        Value::Float(it) if !it.is_finite() => return None,
+        Value::Float(it) => Atom::Float(*it),
+        Value::String(it) => Atom::String(it.clone()),
+        Value::Bytes(it) => Atom::Bytes(it.clone()),
+        Value::Bool(it) => Atom::Bool(*it),
+        Value::Null => Atom::Null,
+        Value::List(items) => {
+            return items
+                .iter()
+                .map(value_to_expression)
+                .collect::<Option<Vec<_>>>()
+                .map(Expression::List);
+        }
+        Value::Map(map) => {
+            return map
+                .map
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Key::Int(it) => Atom::Int(*it),
+                        Key::Uint(it) => Atom::UInt(*it),
+                        Key::Bool(it) => Atom::Bool(*it),
+                        Key::String(it) => Atom::String(it.clone()),
+                    };
+                    value_to_expression(value).map(|value| (Expression::Atom(key), value))
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(Expression::Map);
+        }
+        Value::Timestamp(_) | Value::Duration(_) => {
+            // Written as a call to the function parsing the same text they are serialized to
+            let function = if matches!(value, Value::Timestamp(_)) { "timestamp" } else { "duration" };
+            let passable: PassableValue = DisplayableValue(value.clone()).to_passable();
+            let text = serde_json::to_value(&passable).ok()?["value"].as_str()?.to_string();
+            return Some(Expression::FunctionCall(
+                Box::new(Expression::Ident(Arc::new(function.to_string()))),
+                None,
+                vec![Expression::Atom(Atom::String(Arc::new(text)))],
+            ));
+        }
+        Value::Function(..) => return None,
+    };
+    Some(Expression::Atom(atom))
+}
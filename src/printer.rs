@@ -0,0 +1,263 @@
+use crate::error::EvaluationError;
+use cel_interpreter::Expression;
+use cel_parser::{ArithmeticOp, Atom, Member, RelationOp, UnaryOp};
+
+/**
+ * Precedence levels of the CEL grammar, from the loosest to the tightest binding.
+ * A sub-expression is wrapped in parentheses when its level is lower than the one its position requires.
+ */
+const CONDITIONAL: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const RELATION: u8 = 3;
+const ADD_SUB: u8 = 4;
+const MUL_DIV_MOD: u8 = 5;
+const UNARY: u8 = 6;
+const MEMBER: u8 = 7;
+const PRIMARY: u8 = 8;
+
+/**
+ * Words the parser always reads as keywords, so they cannot be used as identifiers.
+ */
+const KEYWORDS: &[&str] = &["true", "false", "null", "in"];
+
+/**
+ * Prints an expression back into CEL source, using only the parentheses required by the grammar,
+ * so that parsing the result produces the same expression.
+ * @param expression The expression to print
+ * @return The CEL source, or an `unsupported` error if the expression contains a node that cannot be written in CEL,
+ * such as an `uint` literal or a non-finite float
+ */
+pub(crate) fn print(expression: &Expression) -> Result<String, EvaluationError> {
+    let mut out = String::new();
+    write_expression(expression, CONDITIONAL, &mut out)?;
+    Ok(out)
+}
+
+fn level(expression: &Expression) -> u8 {
+    match expression {
+        Expression::Ternary(..) => CONDITIONAL,
+        Expression::Or(..) => OR,
+        Expression::And(..) => AND,
+        Expression::Relation(..) => RELATION,
+        Expression::Arithmetic(_, ArithmeticOp::Add | ArithmeticOp::Subtract, _) => ADD_SUB,
+        Expression::Arithmetic(..) => MUL_DIV_MOD,
+        Expression::Unary(..) => UNARY,
+        Expression::Member(..) | Expression::FunctionCall(_, Some(_), _) => MEMBER,
+        _ => PRIMARY,
+    }
+}
+
+fn write_expression(expression: &Expression, min_level: u8, out: &mut String) -> Result<(), EvaluationError> {
+    if level(expression) < min_level {
+        out.push('(');
+        write_expression(expression, CONDITIONAL, out)?;
+        out.push(')');
+        return Ok(());
+    }
+    match expression {
+        Expression::Ternary(condition, if_true, if_false) => {
+            write_expression(condition, OR, out)?;
+            out.push_str(" ? ");
+            write_expression(if_true, OR, out)?;
+            out.push_str(" : ");
+            write_expression(if_false, CONDITIONAL, out)?;
+        }
+        Expression::Or(left, right) => {
+            write_expression(left, OR, out)?;
+            out.push_str(" || ");
+            write_expression(right, AND, out)?;
+        }
+        Expression::And(left, right) => {
+            write_expression(left, AND, out)?;
+            out.push_str(" && ");
+            write_expression(right, RELATION, out)?;
+        }
+        Expression::Relation(left, op, right) => {
+            write_expression(left, ADD_SUB, out)?;
+            out.push_str(match op {
+                RelationOp::LessThan => " < ",
+                RelationOp::LessThanEq => " <= ",
+                RelationOp::GreaterThan => " > ",
+                RelationOp::GreaterThanEq => " >= ",
+                RelationOp::Equals => " == ",
+                RelationOp::NotEquals => " != ",
+                RelationOp::In => " in ",
+            });
+            write_expression(right, ADD_SUB, out)?;
+        }
+        Expression::Arithmetic(left, op, right) => {
+            let level = level(expression);
+            write_expression(left, level, out)?;
+            out.push_str(match op {
+                ArithmeticOp::Add => " + ",
+                ArithmeticOp::Subtract => " - ",
+                ArithmeticOp::Multiply => " * ",
+                ArithmeticOp::Divide => " / ",
+                ArithmeticOp::Modulus => " % ",
+            });
+            write_expression(right, level + 1, out)?;
+        }
+        Expression::Unary(op, operand) => {
+            out.push_str(match op {
+                UnaryOp::Not => "!",
+                UnaryOp::DoubleNot => "!!",
+                UnaryOp::Minus => "-",
+                UnaryOp::DoubleMinus => "--",
+            });
+            let mut operand_source = String::new();
+            write_expression(operand, MEMBER, &mut operand_source)?;
+            // `-5` is lexed as a negative literal rather than a negated one, so keep them apart
+            if matches!(op, UnaryOp::Minus | UnaryOp::DoubleMinus)
+                && operand_source.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.')
+            {
+                out.push(' ');
+            }
+            out.push_str(&operand_source);
+        }
+        Expression::Member(target, member) => {
+            write_expression(target, MEMBER, out)?;
+            match member.as_ref() {
+                Member::Attribute(name) => {
+                    out.push('.');
+                    write_identifier(name, out)?;
+                }
+                Member::Index(index) => {
+                    out.push('[');
+                    write_expression(index, CONDITIONAL, out)?;
+                    out.push(']');
+                }
+                Member::Fields(fields) => {
+                    out.push('{');
+                    for (i, (name, value)) in fields.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        write_identifier(name, out)?;
+                        out.push_str(": ");
+                        write_expression(value, CONDITIONAL, out)?;
+                    }
+                    out.push('}');
+                }
+            }
+        }
+        Expression::FunctionCall(function, target, args) => {
+            let name = match function.as_ref() {
+                Expression::Ident(name) => name,
+                _ => {
+                    return Err(unsupported("Only identifiers can be called as functions".to_string()));
+                }
+            };
+            if let Some(target) = target {
+                write_expression(target, MEMBER, out)?;
+                out.push('.');
+            }
+            write_identifier(name, out)?;
+            out.push('(');
+            write_list(args, out)?;
+            out.push(')');
+        }
+        Expression::List(items) => {
+            out.push('[');
+            write_list(items, out)?;
+            out.push(']');
+        }
+        Expression::Map(entries) => {
+            out.push('{');
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expression(key, CONDITIONAL, out)?;
+                out.push_str(": ");
+                write_expression(value, CONDITIONAL, out)?;
+            }
+            out.push('}');
+        }
+        Expression::Atom(atom) => write_atom(atom, out)?,
+        Expression::Ident(name) => write_identifier(name, out)?,
+    }
+    Ok(())
+}
+
+fn write_list(items: &[Expression], out: &mut String) -> Result<(), EvaluationError> {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_expression(item, CONDITIONAL, out)?;
+    }
+    Ok(())
+}
+
+fn write_identifier(name: &str, out: &mut String) -> Result<(), EvaluationError> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .map(|c| c == '_' || c.is_ascii_alphabetic())
+        .unwrap_or(false)
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && !KEYWORDS.contains(&name);
+    if !valid {
+        return Err(unsupported(format!("`{}` is not a valid identifier", name)));
+    }
+    out.push_str(name);
+    Ok(())
+}
+
+fn write_atom(atom: &Atom, out: &mut String) -> Result<(), EvaluationError> {
+    match atom {
+        Atom::Int(value) => out.push_str(&value.to_string()),
+        Atom::UInt(value) => {
+            return Err(unsupported(format!("The uint literal {} cannot be written in CEL source", value)));
+        }
+        Atom::Float(value) => {
+            if !value.is_finite() {
+                return Err(unsupported(format!("The float literal {} cannot be written in CEL source", value)));
+            }
+            // Debug formatting always keeps a decimal point or an exponent and round-trips exactly
+            out.push_str(&format!("{:?}", value));
+        }
+        Atom::String(value) => write_string(value, out),
+        Atom::Bytes(value) => write_bytes(value, out),
+        Atom::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+        Atom::Null => out.push_str("null"),
+    }
+    Ok(())
+}
+
+fn write_string(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{07}' => out.push_str("\\a"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0B}' => out.push_str("\\v"),
+            '\u{0C}' => out.push_str("\\f"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_bytes(value: &[u8], out: &mut String) {
+    out.push_str("b\"");
+    for byte in value {
+        match byte {
+            b'"' | b'\\' => out.push_str(&format!("\\x{:02x}", byte)),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+}
+
+fn unsupported(message: String) -> EvaluationError {
+    EvaluationError::Unsupported { message }
+}
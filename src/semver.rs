@@ -0,0 +1,142 @@
+use cel_interpreter::extractors::This;
+use cel_interpreter::objects::{Key, Map};
+use cel_interpreter::{ExecutionError, FunctionContext, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/**
+ * A version number, parsed loosely so that the versions reported by the platforms, such as `17.4`,
+ * `v2.4.1` or `1.0.0-beta.2+1234`, can all be compared following the semantic versioning precedence rules.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Version {
+    /// The numeric components, at least one. Missing components compare as `0`, so `17.4` equals `17.4.0`.
+    /// They are CEL integers, so larger components are invalid.
+    numbers: Vec<i64>,
+    /// The dot separated pre-release identifiers, i.e. `["beta", "2"]`.
+    pre_release: Vec<String>,
+    /// The build metadata, which is ignored when comparing versions.
+    build: String,
+}
+
+impl Version {
+    pub(crate) fn parse(text: &str) -> Option<Version> {
+        let text = text.trim();
+        let text = text.strip_prefix(['v', 'V']).unwrap_or(text);
+        let (text, build) = text.split_once('+').unwrap_or((text, ""));
+        let (core, pre_release) = text.split_once('-').unwrap_or((text, ""));
+        let numbers = core
+            .split('.')
+            .map(|it| {
+                if !it.is_empty() && it.bytes().all(|c| c.is_ascii_digit()) {
+                    it.parse::<i64>().ok()
+                } else {
+                    None
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let pre_release: Vec<String> = if pre_release.is_empty() {
+            vec![]
+        } else {
+            pre_release.split('.').map(str::to_string).collect()
+        };
+        let valid_identifier = |it: &str| !it.is_empty() && it.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-');
+        if !pre_release.iter().all(|it| valid_identifier(it))
+            || (!build.is_empty() && !build.split('.').all(valid_identifier))
+        {
+            return None;
+        }
+        Some(Version {
+            numbers,
+            pre_release,
+            build: build.to_string(),
+        })
+    }
+
+    fn number(&self, index: usize) -> i64 {
+        self.numbers.get(index).copied().unwrap_or(0)
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let numbers = (0..self.numbers.len().max(other.numbers.len()))
+            .map(|i| self.number(i).cmp(&other.number(i)))
+            .find(|it| it.is_ne())
+            .unwrap_or(Ordering::Equal);
+        let pre_release = match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+            // A pre-release version has a lower precedence than the release itself
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => self
+                .pre_release
+                .iter()
+                .zip(other.pre_release.iter())
+                .map(|(a, b)| compare_identifiers(a, b))
+                .find(|it| it.is_ne())
+                .unwrap_or_else(|| self.pre_release.len().cmp(&other.pre_release.len())),
+        };
+        Some(numbers.then(pre_release))
+    }
+}
+
+/**
+ * Numeric identifiers are compared numerically and have a lower precedence than alphanumeric ones,
+ * which are compared in ASCII order.
+ */
+fn compare_identifiers(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+fn parse(ftx: &FunctionContext, text: &str) -> Result<Version, ExecutionError> {
+    Version::parse(text).ok_or_else(|| ftx.error(format!("Invalid version: {}", text)))
+}
+
+/**
+ * Parses a version into a map with its `major`, `minor` and `patch` numbers, its `prerelease` and its `build`,
+ * i.e. `semver("1.2.3-beta.1").minor == 2`.
+ */
+pub fn semver(ftx: &FunctionContext, This(this): This<Arc<String>>) -> Result<Value, ExecutionError> {
+    let version = parse(ftx, &this)?;
+    let mut map: HashMap<Key, Value> = HashMap::new();
+    for (i, name) in ["major", "minor", "patch"].iter().enumerate() {
+        map.insert(Key::String(Arc::new(name.to_string())), Value::Int(version.number(i)));
+    }
+    map.insert(
+        Key::String(Arc::new("prerelease".to_string())),
+        Value::String(Arc::new(version.pre_release.join("."))),
+    );
+    map.insert(Key::String(Arc::new("build".to_string())), Value::String(Arc::new(version.build)));
+    Ok(Value::Map(Map { map: Arc::new(map) }))
+}
+
+/**
+ * Compares two versions, returning `-1`, `0` or `1` if the first one is lower, equal or greater than the second one,
+ * i.e. `versionCompare("10.0", "9.1") == 1`.
+ */
+pub fn version_compare(
+    ftx: &FunctionContext,
+    This(this): This<Arc<String>>,
+    other: Arc<String>,
+) -> Result<i64, ExecutionError> {
+    let ordering = parse(ftx, &this)?.partial_cmp(&parse(ftx, &other)?);
+    Ok(ordering.map(|it| it as i64).unwrap_or(0))
+}
+
+/**
+ * Checks if a version is greater than or equal to the given minimum, i.e. `device.appVersion.versionAtLeast("2.4")`.
+ */
+pub fn version_at_least(
+    ftx: &FunctionContext,
+    This(this): This<Arc<String>>,
+    minimum: Arc<String>,
+) -> Result<bool, ExecutionError> {
+    Ok(version_compare(ftx, This(this), minimum)? >= 0)
+}
@@ -0,0 +1,333 @@
+use cel_interpreter::objects::Key;
+use cel_interpreter::{Context, ExecutionError, FunctionContext, Value};
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, ExecutionError>;
+
+/**
+ * Largest precision accepted by `format`, as the formatter panics on precisions beyond `u16::MAX`.
+ */
+const MAX_PRECISION: usize = 100;
+
+/**
+ * Adds the functions of the `strings` extension to the context.
+ * Every function can be called either as a method on its first operand, i.e. `name.lowerAscii()`,
+ * or as a function, i.e. `lowerAscii(name)`. Indices count Unicode code points rather than bytes.
+ * @param ctx The context to add the functions to
+ */
+pub(crate) fn add_functions(ctx: &mut Context) {
+    ctx.add_function("lowerAscii", lower_ascii);
+    ctx.add_function("upperAscii", upper_ascii);
+    ctx.add_function("trim", trim);
+    ctx.add_function("split", split);
+    ctx.add_function("join", join);
+    ctx.add_function("replace", replace);
+    ctx.add_function("substring", substring);
+    ctx.add_function("indexOf", index_of);
+    ctx.add_function("charAt", char_at);
+    ctx.add_function("format", format);
+}
+
+/**
+ * Resolves the target and the arguments of a call, checking that their number is within the accepted range.
+ */
+pub(crate) fn operands(ftx: &FunctionContext, min: usize, max: usize) -> Result<Vec<Value>> {
+    let mut operands: Vec<Value> = ftx.this.iter().cloned().collect();
+    for arg in &ftx.args {
+        operands.push(ftx.ptx.resolve(arg)?);
+    }
+    if operands.len() < min || operands.len() > max {
+        return Err(ExecutionError::invalid_argument_count(min, operands.len()));
+    }
+    Ok(operands)
+}
+
+fn string(ftx: &FunctionContext, value: &Value) -> Result<Arc<String>> {
+    match value {
+        Value::String(it) => Ok(it.clone()),
+        other => Err(ftx.error(format!("Expected a string, got {:?}", other))),
+    }
+}
+
+fn int(ftx: &FunctionContext, value: &Value) -> Result<i64> {
+    match value {
+        Value::Int(it) => Ok(*it),
+        Value::UInt(it) => i64::try_from(*it).map_err(|_| ftx.error(format!("Integer {} is out of range", it))),
+        other => Err(ftx.error(format!("Expected an integer, got {:?}", other))),
+    }
+}
+
+/**
+ * Converts a code point index into the byte offset of that character, accepting the length of the string.
+ */
+fn byte_offset(ftx: &FunctionContext, text: &str, index: i64) -> Result<usize> {
+    let out_of_range = || {
+        let length = text.chars().count();
+        ftx.error(format!("Index {} is out of range for a string of {} characters", index, length))
+    };
+    if index < 0 {
+        return Err(out_of_range());
+    }
+    text.char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .nth(index as usize)
+        .ok_or_else(out_of_range)
+}
+
+/**
+ * `"Hello".lowerAscii() == "hello"`, only ASCII letters are converted.
+ */
+pub fn lower_ascii(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 1, 1)?;
+    Ok(Value::String(Arc::new(string(ftx, &operands[0])?.to_ascii_lowercase())))
+}
+
+/**
+ * `"Hello".upperAscii() == "HELLO"`, only ASCII letters are converted.
+ */
+pub fn upper_ascii(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 1, 1)?;
+    Ok(Value::String(Arc::new(string(ftx, &operands[0])?.to_ascii_uppercase())))
+}
+
+/**
+ * `" hello\n".trim() == "hello"`, removing the leading and trailing Unicode whitespace.
+ */
+pub fn trim(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 1, 1)?;
+    Ok(Value::String(Arc::new(string(ftx, &operands[0])?.trim().to_string())))
+}
+
+/**
+ * `"en_US".split("_") == ["en", "US"]`. An optional limit caps the number of parts,
+ * the last one containing the rest of the string, i.e. `"a,b,c".split(",", 2) == ["a", "b,c"]`.
+ * A negative limit returns all the parts. An empty separator splits the string into its characters,
+ * i.e. `"abc".split("") == ["a", "b", "c"]`.
+ */
+pub fn split(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 2, 3)?;
+    let text = string(ftx, &operands[0])?;
+    let separator = string(ftx, &operands[1])?;
+    let limit = match operands.get(2) {
+        Some(limit) => int(ftx, limit)?,
+        None => -1,
+    };
+    let parts: Vec<&str> = match limit {
+        0 => vec![],
+        limit if separator.is_empty() => {
+            let mut parts = vec![];
+            let mut rest = text.as_str();
+            while let Some(c) = rest.chars().next() {
+                if parts.len() as i64 == limit - 1 {
+                    parts.push(rest);
+                    break;
+                }
+                parts.push(&rest[..c.len_utf8()]);
+                rest = &rest[c.len_utf8()..];
+            }
+            parts
+        }
+        limit if limit < 0 => text.split(separator.as_str()).collect(),
+        limit => text.splitn(limit as usize, separator.as_str()).collect(),
+    };
+    let parts = parts.into_iter().map(|it| Value::String(Arc::new(it.to_string()))).collect::<Vec<_>>();
+    Ok(Value::List(Arc::new(parts)))
+}
+
+/**
+ * `["a", "b"].join(", ") == "a, b"`, the separator being empty if it is not given.
+ */
+pub fn join(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 1, 2)?;
+    let items = match &operands[0] {
+        Value::List(items) => items.clone(),
+        other => return Err(ftx.error(format!("Expected a list, got {:?}", other))),
+    };
+    let separator = match operands.get(1) {
+        Some(separator) => string(ftx, separator)?,
+        None => Arc::new(String::new()),
+    };
+    let items = items
+        .iter()
+        .map(|it| string(ftx, it).map(|it| it.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Value::String(Arc::new(items.join(separator.as_str()))))
+}
+
+/**
+ * `"a-b-c".replace("-", "_") == "a_b_c"`. An optional limit caps the number of replacements, negative meaning all of them.
+ */
+pub fn replace(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 3, 4)?;
+    let text = string(ftx, &operands[0])?;
+    let from = string(ftx, &operands[1])?;
+    let to = string(ftx, &operands[2])?;
+    let replaced = match operands.get(3).map(|it| int(ftx, it)).transpose()? {
+        Some(limit) if limit >= 0 => text.replacen(from.as_str(), to.as_str(), limit as usize),
+        _ => text.replace(from.as_str(), to.as_str()),
+    };
+    Ok(Value::String(Arc::new(replaced)))
+}
+
+/**
+ * `"en_US".substring(3) == "US"` and `"en_US".substring(0, 2) == "en"`, the end being excluded.
+ */
+pub fn substring(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 2, 3)?;
+    let text = string(ftx, &operands[0])?;
+    let start = byte_offset(ftx, &text, int(ftx, &operands[1])?)?;
+    let end = match operands.get(2) {
+        Some(end) => byte_offset(ftx, &text, int(ftx, end)?)?,
+        None => text.len(),
+    };
+    if start > end {
+        return Err(ftx.error("The start of the substring is after its end"));
+    }
+    Ok(Value::String(Arc::new(text[start..end].to_string())))
+}
+
+/**
+ * `"hello".indexOf("l") == 2`, or `-1` if the string is not found. An optional offset sets where the search starts,
+ * i.e. `"hello".indexOf("l", 3) == 3`.
+ */
+pub fn index_of(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 2, 3)?;
+    let text = string(ftx, &operands[0])?;
+    let needle = string(ftx, &operands[1])?;
+    let start = match operands.get(2) {
+        Some(offset) => byte_offset(ftx, &text, int(ftx, offset)?)?,
+        None => 0,
+    };
+    let index = text[start..]
+        .find(needle.as_str())
+        .map(|offset| text[..start + offset].chars().count() as i64)
+        .unwrap_or(-1);
+    Ok(Value::Int(index))
+}
+
+/**
+ * `"hello".charAt(1) == "e"`, or an empty string for the index right after the last character.
+ */
+pub fn char_at(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 2, 2)?;
+    let text = string(ftx, &operands[0])?;
+    let start = byte_offset(ftx, &text, int(ftx, &operands[1])?)?;
+    let character = text[start..].chars().next().map(String::from).unwrap_or_default();
+    Ok(Value::String(Arc::new(character)))
+}
+
+/**
+ * `"%s has %d items".format([name, 3])`, supporting `%s`, `%d`, `%f` with an optional precision such as `%.2f`,
+ * `%e`, `%x`, `%X`, `%o`, `%b` and `%%`.
+ */
+pub fn format(ftx: &FunctionContext) -> Result<Value> {
+    let operands = operands(ftx, 2, 2)?;
+    let pattern = string(ftx, &operands[0])?;
+    let args = match &operands[1] {
+        Value::List(args) => args.clone(),
+        other => return Err(ftx.error(format!("Expected a list of arguments, got {:?}", other))),
+    };
+    let mut args = args.iter();
+    let mut out = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut precision = None;
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(|it| it.is_ascii_digit()) {
+                digits.push(digit);
+            }
+            precision = match digits.parse::<usize>() {
+                Ok(precision) if precision <= MAX_PRECISION => Some(precision),
+                Ok(_) => return Err(ftx.error(format!("The precision in format cannot exceed {}", MAX_PRECISION))),
+                Err(_) => return Err(ftx.error("Invalid precision in format")),
+            };
+        }
+        let verb = chars.next().ok_or_else(|| ftx.error("Unterminated format clause"))?;
+        if verb == '%' {
+            out.push('%');
+            continue;
+        }
+        let arg = args
+            .next()
+            .ok_or_else(|| ftx.error(format!("Missing argument for the format clause %{}", verb)))?;
+        let formatted = match (verb, arg) {
+            ('s', arg) => display(arg),
+            ('d', Value::Int(it)) => it.to_string(),
+            ('d', Value::UInt(it)) => it.to_string(),
+            ('f', Value::Float(it)) => format!("{:.*}", precision.unwrap_or(6), it),
+            ('f', Value::Int(it)) => format!("{:.*}", precision.unwrap_or(6), *it as f64),
+            ('f', Value::UInt(it)) => format!("{:.*}", precision.unwrap_or(6), *it as f64),
+            ('e', Value::Float(it)) => format!("{:.*e}", precision.unwrap_or(6), it),
+            ('x', Value::Int(it)) => format_radix(*it, |it| format!("{:x}", it)),
+            ('x', Value::UInt(it)) => format!("{:x}", it),
+            ('x', Value::String(it)) => it.bytes().map(|b| format!("{:02x}", b)).collect(),
+            ('X', Value::Int(it)) => format_radix(*it, |it| format!("{:X}", it)),
+            ('X', Value::UInt(it)) => format!("{:X}", it),
+            ('X', Value::String(it)) => it.bytes().map(|b| format!("{:02X}", b)).collect(),
+            ('o', Value::Int(it)) => format_radix(*it, |it| format!("{:o}", it)),
+            ('o', Value::UInt(it)) => format!("{:o}", it),
+            ('b', Value::Int(it)) => format_radix(*it, |it| format!("{:b}", it)),
+            ('b', Value::UInt(it)) => format!("{:b}", it),
+            ('b', Value::Bool(it)) => it.to_string(),
+            (verb, arg) => {
+                return Err(ftx.error(format!("The format clause %{} does not support {:?}", verb, arg)));
+            }
+        };
+        out.push_str(&formatted);
+    }
+    Ok(Value::String(Arc::new(out)))
+}
+
+/**
+ * Formats negative integers with a sign rather than as their two's complement.
+ */
+fn format_radix(value: i64, format: impl Fn(u64) -> String) -> String {
+    if value < 0 {
+        format!("-{}", format(value.unsigned_abs()))
+    } else {
+        format(value as u64)
+    }
+}
+
+/**
+ * The text of a value for the `%s` clause, with lists and maps written as CEL literals.
+ */
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(it) => it.to_string(),
+        Value::Int(it) => it.to_string(),
+        Value::UInt(it) => it.to_string(),
+        Value::Float(it) => it.to_string(),
+        Value::Bool(it) => it.to_string(),
+        Value::Null => "null".to_string(),
+        Value::Bytes(it) => String::from_utf8_lossy(it).to_string(),
+        Value::Timestamp(it) => it.to_rfc3339(),
+        Value::Duration(it) => format!("{}s", it.num_milliseconds() as f64 / 1000.0),
+        Value::List(items) => format!("[{}]", items.iter().map(display).collect::<Vec<_>>().join(", ")),
+        Value::Map(map) => {
+            let mut entries: Vec<String> = map
+                .map
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Key::String(it) => it.to_string(),
+                        Key::Int(it) => it.to_string(),
+                        Key::Uint(it) => it.to_string(),
+                        Key::Bool(it) => it.to_string(),
+                    };
+                    format!("{}: {}", key, display(value))
+                })
+                .collect();
+            entries.sort();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Value::Function(name, _) => name.to_string(),
+    }
+}
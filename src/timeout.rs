@@ -0,0 +1,64 @@
+use crate::models::{HostTimeouts, PassableValue};
+use futures::future::{pending, select, Either, FutureExt, Shared};
+use futures_timer::Delay;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/**
+ * Deadlines of the host calls of an evaluation, and the values used for the properties which miss them.
+ * The deadline of the evaluation starts when it is created and is shared by every call made afterwards,
+ * so a slow host cannot hold the evaluation for longer than that in total.
+ */
+#[derive(Clone, Default)]
+pub(crate) struct Deadlines {
+    host_call: Option<Duration>,
+    evaluation: Option<Shared<Delay>>,
+    fallbacks: Arc<HashMap<String, PassableValue>>,
+}
+
+impl Deadlines {
+    /**
+     * Starts the deadline of the evaluation.
+     * @param timeouts The timeouts of each host call and of all of them together, unlimited if not set
+     * @param fallbacks The values of the properties which time out, named by their object and name
+     */
+    pub(crate) fn start(timeouts: Option<&HostTimeouts>, fallbacks: Option<&HashMap<String, PassableValue>>) -> Self {
+        Deadlines {
+            host_call: timeouts.and_then(|it| it.host_call_millis).map(Duration::from_millis),
+            evaluation: timeouts
+                .and_then(|it| it.evaluation_millis)
+                .map(|it| Delay::new(Duration::from_millis(it)).shared()),
+            fallbacks: Arc::new(fallbacks.cloned().unwrap_or_default()),
+        }
+    }
+
+    /**
+     * Waits for a host call until it completes or a deadline elapses.
+     * The deadlines are checked first, so the host is not called at all once the evaluation deadline elapsed.
+     * @param call The pending host call
+     * @return The result of the call, or `None` if it timed out
+     */
+    pub(crate) async fn run<F: Future>(&self, call: F) -> Option<F::Output> {
+        let host_call = match self.host_call {
+            Some(timeout) => Either::Left(Delay::new(timeout)),
+            None => Either::Right(pending()),
+        };
+        let evaluation = match &self.evaluation {
+            Some(deadline) => Either::Left(deadline.clone()),
+            None => Either::Right(pending()),
+        };
+        match select(select(host_call, evaluation), Box::pin(call)).await {
+            Either::Left(_) => None,
+            Either::Right((output, _)) => Some(output),
+        }
+    }
+
+    /**
+     * The value declared for a property which timed out, i.e. `computed.daysSinceEvent`.
+     */
+    pub(crate) fn fallback(&self, property: &str) -> Option<PassableValue> {
+        self.fallbacks.get(property).cloned()
+    }
+}
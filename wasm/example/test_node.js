@@ -8,7 +8,7 @@ import *  as wasm from "../target/node/supercel_wasm";
  *
  * @param name - The name of the computed property or function being invoked.
  * @param args - JSON string of the arguments for the function.
- * @returns JSON-serialized string of the computed property value.
+ * @returns JSON-serialized string of the computed property value, or a Promise of it.
  * */
 class WasmHostContext {
     computed_property(name, args) {
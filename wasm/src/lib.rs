@@ -1,7 +1,11 @@
 use std::sync::{Arc};
 
-use wasm_bindgen::JsValue;
+use async_trait::async_trait;
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen_futures::js_sys;
+use wasm_bindgen_futures::js_sys::Promise;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
 pub use cel_eval::HostContext;
 
 
@@ -29,6 +33,9 @@ extern "C" {
     #[wasm_bindgen(method, catch)]
     fn device_property(this: &JsHostContext, name: String, args: String) -> Result<JsValue, JsValue>;
 
+    #[wasm_bindgen(method, catch)]
+    fn property(this: &JsHostContext, namespace: String, name: String, args: String) -> Result<JsValue, JsValue>;
+
 }
 
 /**
@@ -56,30 +63,72 @@ impl HostContextAdapter {
     }
 }
 
+impl HostContextAdapter {
+    /**
+     * Awaits the result of a JS Host context method, which returns either a string or a Promise of a string.
+     * Thrown errors, rejections and values which are not strings resolve to a `{"error": "..."}` object,
+     * which fails the evaluation with a `host_error` naming the property.
+     */
+    async fn resolve(result: Result<JsValue, JsValue>) -> String {
+        let value = match result {
+            Ok(value) => match value.dyn_into::<Promise>() {
+                Ok(promise) => JsFuture::from(promise).await,
+                Err(value) => Ok(value),
+            },
+            Err(err) => Err(err),
+        };
+        match value {
+            Ok(value) => value
+                .as_string()
+                .unwrap_or_else(|| HostContextAdapter::failure(format!("Expected a JSON string, got {:?}", value))),
+            Err(err) => {
+                let reason = match err.dyn_ref::<js_sys::Error>() {
+                    Some(err) => err.message().into(),
+                    None => err.as_string().unwrap_or_else(|| format!("{:?}", err)),
+                };
+                HostContextAdapter::failure(reason)
+            }
+        }
+    }
+
+    fn failure(reason: String) -> String {
+        let reason = js_sys::JSON::stringify(&JsValue::from_str(&reason))
+            .ok()
+            .and_then(|it| it.as_string())
+            .unwrap_or_default();
+        format!("{{\"error\":{}}}", reason)
+    }
+}
+
+#[async_trait(?Send)]
 impl HostContext for HostContextAdapter {
 
     /**
      * This method is used to call the computed property method on the JS Host context.
      * It proxies evaluator calls for `platform.something(arg)` to the JS Host context itself.
      */
-    fn computed_property(&self, name: String, args: String) -> String {
-        let context = Arc::clone(&self.context);
-        let promise = context.computed_property(name.clone(), args.clone());
-        let result = promise.expect("Did not receive the proper result from computed").as_string();
-        result
-            .clone()
-            .expect(
-                format!("Could not deserialize the result from computed property - Is some: {}", result.is_some()).as_str())
+    async fn computed_property(&self, name: String, args: String) -> String {
+        HostContextAdapter::resolve(self.context.computed_property(name, args)).await
     }
 
-    fn device_property(&self, name: String, args: String) -> String {
-        let context = Arc::clone(&self.context);
-        let promise = context.device_property(name.clone(), args.clone());
-        let result = promise.expect("Did not receive the proper result from computed").as_string();
-        result
-            .clone()
-            .expect(
-                format!("Could not deserialize the result from computed property - Is some: {}", result.is_some()).as_str())
+    async fn device_property(&self, name: String, args: String) -> String {
+        HostContextAdapter::resolve(self.context.device_property(name, args)).await
+    }
+
+    /**
+     * Calls the `property` method of the JS Host context if it has one,
+     * otherwise `computed` and `device` properties are resolved by their own methods.
+     */
+    async fn property(&self, namespace: String, name: String, args: String) -> String {
+        let generic = js_sys::Reflect::get(&self.context, &JsValue::from_str("property"))
+            .map(|it| it.is_function())
+            .unwrap_or(false);
+        match namespace.as_str() {
+            _ if generic => HostContextAdapter::resolve(self.context.property(namespace, name, args)).await,
+            "computed" => self.computed_property(name, args).await,
+            "device" => self.device_property(name, args).await,
+            _ => HostContextAdapter::failure(format!("No host object named {}", namespace)),
+        }
     }
 
 }
@@ -92,13 +141,25 @@ unsafe impl Sync for HostContextAdapter {}
 #[wasm_bindgen]
 pub async fn evaluate_with_context(definition: String, context: JsHostContext) -> Result<String, JsValue> {
     let adapter = Arc::new(HostContextAdapter::new(context));
-    Ok(cel_eval::evaluate_with_context(definition, adapter))
+    Ok(cel_eval::evaluate_with_context_async(definition, adapter).await)
 }
 
 #[wasm_bindgen]
 pub async fn evaluate_ast_with_context(definition: String, context: JsHostContext) -> Result<String, JsValue> {
     let adapter = Arc::new(HostContextAdapter::new(context));
-    Ok(cel_eval::evaluate_ast_with_context(definition, adapter))
+    Ok(cel_eval::evaluate_ast_with_context_async(definition, adapter).await)
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_rules(definition: String, context: JsHostContext) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_rules_async(definition, adapter).await)
+}
+
+#[wasm_bindgen]
+pub async fn partial_evaluate(definition: String, context: JsHostContext) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::partial_evaluate_async(definition, adapter).await)
 }
 
 #[wasm_bindgen]
@@ -111,6 +172,58 @@ pub async fn parse_into_ast(expression: String) -> Result<String, JsValue> {
     Ok(cel_eval::parse_to_ast(expression))
 }
 
+#[wasm_bindgen]
+pub async fn ast_to_expression(ast: String) -> Result<String, JsValue> {
+    Ok(cel_eval::ast_to_expression(ast))
+}
+
+#[wasm_bindgen]
+pub async fn analyze_expression(expression: String, namespaces: Vec<String>) -> Result<String, JsValue> {
+    Ok(cel_eval::analyze_expression(expression, namespaces))
+}
+
+#[wasm_bindgen]
+pub async fn optimize_ast(ast: String) -> Result<String, JsValue> {
+    Ok(cel_eval::optimize_ast(ast))
+}
+
+#[wasm_bindgen]
+pub async fn analyze_ast(ast: String, namespaces: Vec<String>) -> Result<String, JsValue> {
+    Ok(cel_eval::analyze_ast(ast, namespaces))
+}
+
+#[wasm_bindgen]
+pub async fn check_expression(expression: String, declarations: String) -> Result<String, JsValue> {
+    Ok(cel_eval::check_expression(expression, declarations))
+}
+
+/**
+ * A CEL expression compiled once, which can be evaluated many times with different contexts.
+ */
+#[wasm_bindgen]
+pub struct CompiledExpression {
+    inner: Arc<cel_eval::CompiledExpression>,
+}
+
+#[wasm_bindgen]
+impl CompiledExpression {
+    #[wasm_bindgen(constructor)]
+    pub fn new(expression: String) -> CompiledExpression {
+        CompiledExpression {
+            inner: Arc::new(cel_eval::CompiledExpression::new(expression)),
+        }
+    }
+
+    /**
+     * Evaluates the expression, returning a Promise of the JSON result.
+     */
+    pub fn evaluate(&self, definition: String, context: JsHostContext) -> Promise {
+        let inner = Arc::clone(&self.inner);
+        let adapter = Arc::new(HostContextAdapter::new(context));
+        future_to_promise(async move { Ok(JsValue::from(inner.evaluate_async(definition, adapter).await)) })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]